@@ -0,0 +1,268 @@
+// Advanced Configuration and Power Interface
+// The firmware leaves a set of tables in memory describing the hardware. We
+// only care about one of them for now, the MADT, which tells us where the
+// interrupt controllers live and how the legacy ISA IRQs are wired to them.
+//   see: https://wiki.osdev.org/RSDP
+//   see: https://wiki.osdev.org/RSDT
+//   see: https://wiki.osdev.org/MADT
+// To find the tables we first look for the Root System Description Pointer in
+// the BIOS memory areas. It points at the RSDT (or XSDT on ACPI 2.0+) which is
+// a list of physical addresses of every other table. Every table starts with
+// the same header so we can find the MADT by its "APIC" signature.
+// All addresses in the tables are physical so everything is read through the
+// bootloader's mapping of physical memory.
+
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use x86_64::VirtAddr;
+
+/// Signature at the start of the RSDP.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Signature of the MADT, "Multiple APIC Description Table".
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+/// Size of the header every System Description Table starts with.
+const SDT_HEADER_SIZE: u64 = 36;
+
+/// Physical address where the BIOS stores the segment of the Extended BIOS
+/// Data Area.
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+/// The main BIOS area below 1 MiB that may hold the RSDP.
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+/// How many of each MADT entry we keep.
+pub const MAX_PROCESSORS: usize = 256;
+pub const MAX_IO_APICS: usize = 8;
+pub const MAX_OVERRIDES: usize = 16;
+
+/// Types of the entries in the MADT that we understand.
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// An I/O APIC described by the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    /// Physical address of its registers.
+    pub address: u64,
+    /// The first Global System Interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// A legacy ISA IRQ that is not identity mapped to a Global System Interrupt.
+/// eg. the PIT is usually IRQ0 but wired to GSI 2.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: bits 0-1 polarity, bits 2-3 trigger mode.
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    /// Polarity bits `0b11` mean active low.
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    /// Trigger mode bits `0b11` mean level triggered.
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// Everything we learned from the MADT.
+#[derive(Debug)]
+pub struct Madt {
+    /// Physical address of the local APIC registers.
+    pub local_apic_address: u64,
+    /// Whether legacy 8259 PICs are also installed and need masking.
+    pub has_8259_pics: bool,
+    /// The APIC ids of every enabled processor.
+    pub processors: ArrayVec<u8, MAX_PROCESSORS>,
+    pub io_apics: ArrayVec<IoApicInfo, MAX_IO_APICS>,
+    pub overrides: ArrayVec<InterruptOverride, MAX_OVERRIDES>,
+}
+
+impl Madt {
+    /// Find the Global System Interrupt for a legacy ISA IRQ and its override,
+    /// if there is one.
+    pub fn isa_irq_to_gsi(&self, irq: u8) -> (u32, Option<InterruptOverride>) {
+        match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => (o.gsi, Some(*o)),
+            None => (irq as u32, None),
+        }
+    }
+}
+
+/// Locate and parse the MADT.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`.
+/// # Safety
+///    There is none. Must follow the rules.
+pub unsafe fn find_madt(physical_memory_offset: VirtAddr) -> Option<Madt> {
+    let physical = PhysicalMemory(physical_memory_offset);
+    let rsdp = physical.find_rsdp()?;
+    let madt = physical.find_table(rsdp, MADT_SIGNATURE)?;
+    Some(physical.parse_madt(madt))
+}
+
+/// Reads from physical memory through the bootloader's mapping.
+struct PhysicalMemory(VirtAddr);
+
+impl PhysicalMemory {
+    unsafe fn read<T: Copy>(&self, addr: u64) -> T {
+        let ptr: *const T = (self.0 + addr).as_ptr();
+        core::ptr::read_unaligned(ptr)
+    }
+
+    /// Every ACPI structure is valid only if all its bytes sum to zero.
+    unsafe fn checksum_ok(&self, addr: u64, len: u64) -> bool {
+        let sum = (0..len).fold(0u8, |sum, i| sum.wrapping_add(self.read::<u8>(addr + i)));
+        sum == 0
+    }
+
+    /// Search the first KiB of the EBDA and then the main BIOS area for the
+    /// RSDP. It's always on a 16 byte boundary.
+    unsafe fn find_rsdp(&self) -> Option<u64> {
+        let ebda = (self.read::<u16>(EBDA_SEGMENT_POINTER) as u64) << 4;
+        let candidates = (ebda..ebda + 1024).chain(BIOS_AREA_START..BIOS_AREA_END);
+        candidates.step_by(16).find(|&addr| {
+            self.read::<[u8; 8]>(addr) == *RSDP_SIGNATURE && self.checksum_ok(addr, 20)
+        })
+    }
+
+    /// Walk the RSDT, or the XSDT on ACPI 2.0+, looking for a table with the
+    /// given signature.
+    unsafe fn find_table(&self, rsdp: u64, signature: &[u8; 4]) -> Option<u64> {
+        let revision: u8 = self.read(rsdp + 15);
+        let (root, entry_size) = if revision >= 2 {
+            (self.read::<u64>(rsdp + 24), 8)
+        } else {
+            (self.read::<u32>(rsdp + 16) as u64, 4)
+        };
+        let length = self.read::<u32>(root + 4) as u64;
+        if !self.checksum_ok(root, length) {
+            return None;
+        }
+
+        let entries = (length - SDT_HEADER_SIZE) / entry_size;
+        (0..entries)
+            .map(|i| {
+                let entry = root + SDT_HEADER_SIZE + i * entry_size;
+                if entry_size == 8 {
+                    self.read::<u64>(entry)
+                } else {
+                    self.read::<u32>(entry) as u64
+                }
+            })
+            .find(|&table| {
+                self.read::<[u8; 4]>(table) == *signature
+                    && self.checksum_ok(table, self.read::<u32>(table + 4) as u64)
+            })
+    }
+
+    unsafe fn parse_madt(&self, madt: u64) -> Madt {
+        let length = self.read::<u32>(madt + 4) as u64;
+        let flags: u32 = self.read(madt + SDT_HEADER_SIZE + 4);
+        let mut result = Madt {
+            local_apic_address: self.read::<u32>(madt + SDT_HEADER_SIZE) as u64,
+            has_8259_pics: flags & 1 == 1,
+            processors: ArrayVec::new(),
+            io_apics: ArrayVec::new(),
+            overrides: ArrayVec::new(),
+        };
+
+        // Variable length entries follow the local APIC address and flags
+        let mut entry = madt + SDT_HEADER_SIZE + 8;
+        while entry + 2 <= madt + length {
+            let entry_type: u8 = self.read(entry);
+            let entry_length: u8 = self.read(entry + 1);
+            match entry_type {
+                MADT_LOCAL_APIC => {
+                    // Bit 0: enabled, bit 1: can be enabled later
+                    if self.read::<u32>(entry + 4) & 0b11 != 0 {
+                        result.processors.push(self.read(entry + 3));
+                    }
+                }
+                MADT_IO_APIC => result.io_apics.push(IoApicInfo {
+                    id: self.read(entry + 2),
+                    address: self.read::<u32>(entry + 4) as u64,
+                    gsi_base: self.read(entry + 8),
+                }),
+                MADT_INTERRUPT_SOURCE_OVERRIDE => result.overrides.push(InterruptOverride {
+                    irq: self.read(entry + 3),
+                    gsi: self.read(entry + 4),
+                    flags: self.read(entry + 8),
+                }),
+                MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    result.local_apic_address = self.read(entry + 4);
+                }
+                _ => {}
+            }
+            if entry_length == 0 {
+                // A malformed entry, bail rather than loop forever
+                break;
+            }
+            entry += entry_length as u64;
+        }
+
+        result
+    }
+}
+
+/// A list with room for `N` entries stored inline. The tables are parsed once
+/// at boot and kept forever, this keeps them from sitting on the heap.
+#[derive(Clone, Copy)]
+pub struct ArrayVec<T: Copy, const N: usize> {
+    items: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> ArrayVec<T, N> {
+    pub const fn new() -> Self {
+        ArrayVec {
+            items: [MaybeUninit::uninit(); N],
+            len: 0,
+        }
+    }
+
+    /// Add `item` to the end, or drop it if the list is full.
+    pub fn push(&mut self, item: T) {
+        if let Some(slot) = self.items.get_mut(self.len) {
+            slot.write(item);
+            self.len += 1;
+        }
+    }
+}
+
+impl<T: Copy, const N: usize> Default for ArrayVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy, const N: usize> Deref for ArrayVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // The first `len` items have been written
+        unsafe { core::slice::from_raw_parts(self.items.as_ptr().cast(), self.len) }
+    }
+}
+
+impl<T: Copy, const N: usize> DerefMut for ArrayVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.items.as_mut_ptr().cast(), self.len) }
+    }
+}
+
+impl<T: Copy + core::fmt::Debug, const N: usize> core::fmt::Debug for ArrayVec<T, N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
@@ -0,0 +1,386 @@
+// Address Spaces
+// Every process gets page tables of its own, so it can only see its own
+// memory and the kernel's. An `AddressSpace` is a level 4 table whose
+// entries for the user range, USER_START to USER_END, belong to it alone.
+// All the other entries are copied from the kernel's table, so they point at
+// the same level 3 tables and the kernel looks the same from every address
+// space. That only works for P4 entries that exist when the copy is made,
+// so `init` makes sure every entry the kernel may grow into later exists up
+// front.
+//   see: https://wiki.osdev.org/Paging
+// The kernel isn't in the higher half, the bootloader puts it and the
+// physical memory map in the first few P4 entries and we put the heap and
+// everything else far above them. The user range is a gap in between.
+// Switching address spaces means loading CR3, which normally throws away
+// every TLB entry. With PCIDs every address space tags its TLB entries with
+// an id of its own and only the entries of the one switched to are thrown
+// away, so changes the kernel made to its mappings meanwhile can't linger.
+//   see: https://wiki.osdev.org/TLB
+use crate::frame_allocator::GlobalFrameAllocator;
+use crate::vma::{self, RegionKind};
+use crate::{memory, vma::VmaError};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::tlb::Pcid;
+use x86_64::registers::control::{Cr3, Cr4, Cr4Flags};
+use x86_64::structures::paging::mapper::{MapToError, Translate};
+use x86_64::structures::paging::page_table::{FrameError, PageTableEntry};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+    PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// The part of every address space that belongs to the process.
+pub const USER_START: u64 = 0x_0000_1000_0000_0000;
+pub const USER_END: u64 = 0x_0000_4000_0000_0000;
+/// Virtual memory covered by one P4 entry.
+const P4_ENTRY_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+/// The P4 entries of the user range.
+const USER_SLOTS: core::ops::Range<usize> =
+    (USER_START / P4_ENTRY_SIZE) as usize..(USER_END / P4_ENTRY_SIZE) as usize;
+/// Ranges the kernel maps more memory into after boot.
+const KERNEL_GROWTH: [(u64, u64); 2] = [
+    (
+        crate::allocator::HEAP_START as u64,
+        crate::allocator::HEAP_RESERVED as u64,
+    ),
+    (vma::DYNAMIC_START, vma::DYNAMIC_END - vma::DYNAMIC_START),
+];
+/// How many PCIDs there are, 0 is the kernel's.
+const PCID_COUNT: usize = 4096;
+
+// The kernel's own level 4 table, the one the bootloader left in CR3.
+static KERNEL_P4: AtomicU64 = AtomicU64::new(0);
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+// Bit set for every PCID in use.
+static PCIDS: Mutex<[u64; PCID_COUNT / 64]> = Mutex::new({
+    let mut pcids = [0; PCID_COUNT / 64];
+    pcids[0] = 1;
+    pcids
+});
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    OutOfFrames,
+    /// The address is outside the user range.
+    NotUserAddress(VirtAddr),
+    NotMapped(VirtAddr),
+    Map(MapToError<Size4KiB>),
+    Vma(VmaError),
+}
+
+/// Get ready to make address spaces. Call once the kernel's virtual memory
+/// is tracked.
+pub fn init() -> Result<(), AddressSpaceError> {
+    let (frame, _) = Cr3::read();
+    KERNEL_P4.store(frame.start_address().as_u64(), Ordering::Relaxed);
+    vma::reserve(
+        VirtAddr::new(USER_START),
+        USER_END - USER_START,
+        RegionKind::User,
+        "user space",
+    )
+    .map_err(AddressSpaceError::Vma)?;
+
+    // Fill in every P4 entry the kernel can grow into so copies see it
+    memory::with_mapper(|mapper| {
+        let table = mapper.level_4_table();
+        for (start, size) in KERNEL_GROWTH {
+            let first = (start / P4_ENTRY_SIZE) as usize % 512;
+            let last = ((start + size - 1) / P4_ENTRY_SIZE) as usize % 512;
+            for entry in table.iter_mut().take(last + 1).skip(first) {
+                if entry.is_unused() {
+                    let frame = zeroed_frame().ok_or(AddressSpaceError::OutOfFrames)?;
+                    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    if supports_pcid() {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+        PCID_ENABLED.store(true, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Whether the CPU can tag TLB entries with a PCID.
+pub fn supports_pcid() -> bool {
+    // CPUID leaf 1, ECX bit 17
+    let cpuid = crate::cpuid(1);
+    cpuid.ecx & (1 << 17) != 0
+}
+
+/// A level 4 page table with the kernel mapped in and a user range of its
+/// own. Everything it mapped in the user range is freed when dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    p4: PhysFrame,
+    pcid: Option<Pcid>,
+}
+
+impl AddressSpace {
+    /// An address space with nothing mapped in the user range.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let p4 = zeroed_frame().ok_or(AddressSpaceError::OutOfFrames)?;
+        let mut space = AddressSpace {
+            p4,
+            pcid: allocate_pcid(),
+        };
+        let kernel = unsafe { table(kernel_p4()) };
+        let table = space.table();
+        for (i, entry) in kernel.iter().enumerate() {
+            if !USER_SLOTS.contains(&i) {
+                table[i] = entry.clone();
+            }
+        }
+        Ok(space)
+    }
+
+    /// The frame of its level 4 table, what goes in CR3.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.p4
+    }
+
+    pub fn pcid(&self) -> Option<Pcid> {
+        self.pcid
+    }
+
+    /// Whether this is the address space the CPU is using.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4
+    }
+
+    /// Switch the CPU to this address space.
+    ///
+    /// This function is unsafe because the caller must make sure the address
+    /// space is switched away from before it is dropped, and that nothing
+    /// still uses addresses from the user range of the previous one.
+    /// # Safety
+    ///    There is none. Must follow the rules.
+    pub unsafe fn activate(&self) {
+        switch(self.p4, self.pcid);
+    }
+
+    /// Run `f` with a mapper for this address space, active or not.
+    pub fn with_mapper<R>(&mut self, f: impl FnOnce(&mut OffsetPageTable) -> R) -> R {
+        let mut mapper =
+            unsafe { OffsetPageTable::new(self.table(), memory::physical_memory_offset()) };
+        f(&mut mapper)
+    }
+
+    /// Map zeroed frames with `flags` to the `size` bytes at `start`, which
+    /// have to be in the user range.
+    pub fn map_zeroed(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let end = start + size;
+        for addr in [start, end - 1u64] {
+            if !(USER_START..USER_END).contains(&addr.as_u64()) {
+                return Err(AddressSpaceError::NotUserAddress(addr));
+            }
+        }
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(end - 1u64),
+        );
+        self.with_mapper(|mapper| {
+            for page in pages {
+                let frame = zeroed_frame().ok_or(AddressSpaceError::OutOfFrames)?;
+                match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+                    Ok(flush) => flush.ignore(),
+                    Err(err) => {
+                        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                        return Err(AddressSpaceError::Map(err));
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        // Only the active address space can have stale TLB entries
+        if self.is_active() {
+            for page in pages {
+                x86_64::instructions::tlb::flush(page.start_address());
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy `data` to `addr` through the physical memory map, so it works
+    /// whether the address space is active or not and whatever the pages'
+    /// flags are. It all has to be mapped already.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), AddressSpaceError> {
+        let offset = memory::physical_memory_offset();
+        self.with_mapper(|mapper| {
+            let mut done = 0;
+            while done < data.len() {
+                let at = addr + done as u64;
+                let phys = mapper
+                    .translate_addr(at)
+                    .ok_or(AddressSpaceError::NotMapped(at))?;
+                // Up to the end of the page, the next may be anywhere
+                let len = (data.len() - done)
+                    .min((Size4KiB::SIZE - at.as_u64() % Size4KiB::SIZE) as usize);
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        data[done..].as_ptr(),
+                        (offset + phys.as_u64()).as_mut_ptr::<u8>(),
+                        len,
+                    )
+                };
+                done += len;
+            }
+            Ok(())
+        })
+    }
+
+    fn table(&mut self) -> &mut PageTable {
+        unsafe { table(self.p4) }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe { switch_to_kernel() };
+        }
+        let table = self.table();
+        for entry in table.iter_mut().take(USER_SLOTS.end).skip(USER_SLOTS.start) {
+            unsafe { free_table(entry, 4) };
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.p4) };
+        if let Some(pcid) = self.pcid {
+            release_pcid(pcid);
+        }
+    }
+}
+
+/// Switch the CPU back to the kernel's own page table.
+///
+/// This function is unsafe for the same reasons as `AddressSpace::activate`.
+/// # Safety
+///    There is none. Must follow the rules.
+pub unsafe fn switch_to_kernel() {
+    switch(kernel_p4(), None);
+}
+
+unsafe fn switch(p4: PhysFrame, pcid: Option<Pcid>) {
+    if PCID_ENABLED.load(Ordering::Relaxed) {
+        let pcid = pcid.unwrap_or_else(|| Pcid::new(0).unwrap());
+        Cr3::write_pcid(p4, pcid);
+    } else {
+        Cr3::write(p4, Cr3::read().1);
+    }
+}
+
+fn kernel_p4() -> PhysFrame {
+    PhysFrame::containing_address(x86_64::PhysAddr::new(KERNEL_P4.load(Ordering::Relaxed)))
+}
+
+/// The page table in `frame`, through the physical memory map.
+unsafe fn table<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    let virt = memory::physical_memory_offset() + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr()
+}
+
+/// Free everything `entry`, in a table at `level` (4 for the P4), points to:
+/// the frames mapped by it and the tables below it.
+unsafe fn free_table(entry: &mut PageTableEntry, level: u8) {
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(FrameError::FrameNotPresent) => return,
+        Err(FrameError::HugeFrame) => {
+            let start = entry.addr();
+            match level {
+                3 => GlobalFrameAllocator
+                    .deallocate_frame(PhysFrame::<Size1GiB>::containing_address(start)),
+                2 => GlobalFrameAllocator
+                    .deallocate_frame(PhysFrame::<Size2MiB>::containing_address(start)),
+                // The same bit means PAT in a P1 entry
+                1 => GlobalFrameAllocator
+                    .deallocate_frame(PhysFrame::<Size4KiB>::containing_address(start)),
+                // and is reserved in a P4 entry
+                _ => {}
+            }
+            entry.set_unused();
+            return;
+        }
+    };
+    if level > 1 {
+        for entry in table(frame).iter_mut() {
+            free_table(entry, level - 1);
+        }
+    }
+    GlobalFrameAllocator.deallocate_frame(frame);
+    entry.set_unused();
+}
+
+fn zeroed_frame() -> Option<PhysFrame> {
+    let frame: PhysFrame = GlobalFrameAllocator.allocate_frame()?;
+    let virt = memory::physical_memory_offset() + frame.start_address().as_u64();
+    unsafe {
+        virt.as_mut_ptr::<u8>()
+            .write_bytes(0, Size4KiB::SIZE as usize)
+    };
+    Some(frame)
+}
+
+fn allocate_pcid() -> Option<Pcid> {
+    if !PCID_ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    interrupts::without_interrupts(|| {
+        let mut pcids = PCIDS.lock();
+        let (word, bits) = pcids
+            .iter_mut()
+            .enumerate()
+            .find(|(_, bits)| **bits != !0)?;
+        let bit = bits.trailing_ones() as usize;
+        *bits |= 1 << bit;
+        Pcid::new((word * 64 + bit) as u16).ok()
+    })
+}
+
+fn release_pcid(pcid: Pcid) {
+    let pcid = pcid.value() as usize;
+    interrupts::without_interrupts(|| PCIDS.lock()[pcid / 64] &= !(1 << (pcid % 64)));
+}
+
+#[test_case]
+fn test_address_space() {
+    let before = crate::frame_allocator::stats();
+    let mut space = AddressSpace::new().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let addr = VirtAddr::new(USER_START + 0x1000);
+    space.map_zeroed(addr, 2 * 4096, flags).unwrap();
+    assert!(matches!(
+        space.map_zeroed(VirtAddr::new(USER_END), 4096, flags),
+        Err(AddressSpaceError::NotUserAddress(_))
+    ));
+    let ptr = addr.as_mut_ptr::<u64>();
+    interrupts::without_interrupts(|| unsafe {
+        space.activate();
+        assert!(space.is_active());
+        // Still running, so the kernel is there too
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+        switch_to_kernel();
+    });
+    assert!(!space.is_active());
+    space.write(addr + 4090u64, &[1; 12]).unwrap();
+    assert!(matches!(
+        space.write(addr + 2 * 4096u64, &[1]),
+        Err(AddressSpaceError::NotMapped(_))
+    ));
+    // Not mapped in the kernel's own table
+    assert!(unsafe { memory::translate_addr(addr, memory::physical_memory_offset()) }.is_none());
+    drop(space);
+    assert_eq!(crate::frame_allocator::stats(), before);
+}
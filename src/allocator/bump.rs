@@ -0,0 +1,67 @@
+// Bump Allocator
+// The simplest heap there is: memory is handed out by moving a pointer up
+// and nothing is reused until every allocation has been freed, then it starts
+// again from the bottom. Allocating is as fast as it gets but one long lived
+// allocation keeps everything after it from ever being reused.
+//   see: https://os.phil-opp.com/allocator-designs/#bump-allocator
+use super::{align_up, HeapAllocator};
+use core::alloc::Layout;
+use core::ptr;
+
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    /// Where the next allocation starts looking.
+    next: usize,
+    /// How many allocations haven't been freed yet.
+    allocations: usize,
+}
+
+impl HeapAllocator for BumpAllocator {
+    const EMPTY: Self = BumpAllocator {
+        heap_start: 0,
+        heap_end: 0,
+        next: 0,
+        allocations: 0,
+    };
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        *self = BumpAllocator {
+            heap_start: start,
+            heap_end: start + size,
+            next: start,
+            allocations: 0,
+        };
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.heap_end += by;
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let start = align_up(self.next, layout.align());
+        match start.checked_add(layout.size()) {
+            Some(end) if end <= self.heap_end => {
+                self.next = end;
+                self.allocations += 1;
+                start as *mut u8
+            }
+            _ => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, _ptr: *mut u8, _layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
+
+    fn used(&self) -> usize {
+        self.next - self.heap_start
+    }
+}
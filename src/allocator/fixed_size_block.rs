@@ -0,0 +1,109 @@
+// Fixed Size Block Allocator
+// Every allocation is rounded up to one of a few power of two block sizes
+// and freed blocks are kept on a list for their size. Allocating and freeing
+// a block just pops or pushes a list, so it takes the same time however
+// fragmented the heap is, at the cost of the memory lost to rounding up.
+// Lists are filled from a linked list heap when they run dry and blocks are
+// never given back to it. Allocations bigger than the largest block go
+// straight to the linked list heap.
+//   see: https://os.phil-opp.com/allocator-designs/#fixed-size-block-allocator
+use super::HeapAllocator;
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+
+/// The block sizes. A block is also aligned to its size so these must be
+/// powers of two.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// A free block, pointing at the next free block of the same size.
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback: Heap,
+    /// Bytes handed out, counting whole blocks.
+    used: usize,
+}
+
+impl FixedSizeBlockAllocator {
+    fn fallback_allocate(&mut self, layout: Layout) -> *mut u8 {
+        self.fallback
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+}
+
+/// The index of the smallest block `layout` fits in, if any.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required)
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator {
+    const EMPTY: Self = FixedSizeBlockAllocator {
+        list_heads: [const { None }; BLOCK_SIZES.len()],
+        fallback: Heap::empty(),
+        used: 0,
+    };
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        *self = Self::EMPTY;
+        self.fallback.init(start, size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.fallback.extend(by);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let Some(index) = list_index(&layout) else {
+            let ptr = self.fallback_allocate(layout);
+            if !ptr.is_null() {
+                self.used += layout.size();
+            }
+            return ptr;
+        };
+        let block_size = BLOCK_SIZES[index];
+        let ptr = match self.list_heads[index].take() {
+            Some(node) => {
+                self.list_heads[index] = node.next.take();
+                node as *mut ListNode as *mut u8
+            }
+            // No free block of this size, carve a new one out of the fallback
+            None => {
+                self.fallback_allocate(Layout::from_size_align(block_size, block_size).unwrap())
+            }
+        };
+        if !ptr.is_null() {
+            self.used += block_size;
+        }
+        ptr
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let Some(index) = list_index(&layout) else {
+            self.fallback
+                .deallocate(NonNull::new_unchecked(ptr), layout);
+            self.used -= layout.size();
+            return;
+        };
+        // Every block is big enough and aligned for a node
+        let node = ptr as *mut ListNode;
+        node.write(ListNode {
+            next: self.list_heads[index].take(),
+        });
+        self.list_heads[index] = Some(&mut *node);
+        self.used -= BLOCK_SIZES[index];
+    }
+
+    fn size(&self) -> usize {
+        self.fallback.size()
+    }
+
+    fn used(&self) -> usize {
+        self.used
+    }
+}
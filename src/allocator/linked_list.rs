@@ -0,0 +1,47 @@
+// Linked List Allocator
+// Free memory is kept as a list of holes and an allocation takes the first
+// hole it fits in, see the linked_list_allocator crate. Freed memory is merged
+// with the holes next to it so all of it can be used again, but the more the
+// heap is fragmented the longer the list gets and the slower it is to search.
+//   see: https://os.phil-opp.com/allocator-designs/#linked-list-allocator
+use super::HeapAllocator;
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+
+pub struct LinkedListAllocator {
+    heap: Heap,
+}
+
+impl HeapAllocator for LinkedListAllocator {
+    const EMPTY: Self = LinkedListAllocator {
+        heap: Heap::empty(),
+    };
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        self.heap = Heap::empty();
+        self.heap.init(start, size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.heap.extend(by);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        self.heap
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        self.heap.deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+
+    fn size(&self) -> usize {
+        self.heap.size()
+    }
+
+    fn used(&self) -> usize {
+        self.heap.used()
+    }
+}
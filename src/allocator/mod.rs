@@ -0,0 +1,495 @@
+// Kernel Heap
+// The heap lives in its own range of virtual memory starting at HEAP_START
+// which is mapped as it grows. How that memory is handed out is up to one of
+// several designs, all implementing `HeapAllocator`, picked with a cargo
+// feature:
+//   (default)                    linked_list      first fit from a list of holes
+//   bump-allocator               bump             a pointer that only moves up
+//   fixed-size-block-allocator   fixed_size_block power of two free lists
+//   slab-allocator               slab             pages of same sized objects
+//   see: https://os.phil-opp.com/allocator-designs/
+// Run the tests with a feature to check a design, for example
+//   cargo test --features slab-allocator
+#![allow(clippy::new_without_default)]
+use crate::frame_allocator::GlobalFrameAllocator;
+use crate::{memory, println, serial_println};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use stats::Tracked;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+// Arbitrary starting point for the Kernel's heap. 0x4444_4444_0000 chosen
+// because it is a) unused, and b) easy to identify
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+// Kernel's initial heap size; 100 KiB. It grows from there when it runs out.
+pub const HEAP_SIZE: usize = 100 * 1024;
+// Virtual address space set aside for the heap to grow into; 256 MiB. It is
+// reserved in `vma` so nothing else gets mapped there, and has to stop short
+// of the APIC registers at APIC_MMIO_START.
+pub const HEAP_RESERVED: usize = 256 * 1024 * 1024;
+// How big the heap may grow unless changed with `set_heap_limit`; 64 MiB.
+pub const DEFAULT_HEAP_LIMIT: usize = 64 * 1024 * 1024;
+// Grow by at least this much at a time so we aren't mapping a page for every
+// allocation.
+const HEAP_GROWTH: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+pub mod bump;
+pub mod fixed_size_block;
+pub mod linked_list;
+pub mod slab;
+pub mod stats;
+
+#[cfg(any(
+    all(feature = "bump-allocator", feature = "fixed-size-block-allocator"),
+    all(feature = "bump-allocator", feature = "slab-allocator"),
+    all(feature = "fixed-size-block-allocator", feature = "slab-allocator"),
+))]
+compile_error!("only one heap allocator feature can be enabled");
+
+#[cfg(feature = "bump-allocator")]
+type Backend = bump::BumpAllocator;
+#[cfg(feature = "fixed-size-block-allocator")]
+type Backend = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "slab-allocator")]
+type Backend = slab::SlabAllocator;
+#[cfg(not(any(
+    feature = "bump-allocator",
+    feature = "fixed-size-block-allocator",
+    feature = "slab-allocator"
+)))]
+type Backend = linked_list::LinkedListAllocator;
+
+/// The name of the heap design the kernel was built with.
+pub const BACKEND: &str = if cfg!(feature = "bump-allocator") {
+    "bump"
+} else if cfg!(feature = "fixed-size-block-allocator") {
+    "fixed size block"
+} else if cfg!(feature = "slab-allocator") {
+    "slab"
+} else {
+    "linked list"
+};
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_HEAP_LIMIT);
+
+// The #[global_allocator] attribute tells rust what static item we want to use
+// as our OS's global memory allocator. It must implement the
+// alloc::alloc::GlobalAlloc trait.
+#[global_allocator]
+static ALLOCATOR: Tracked<Locked<GrowableHeap<Backend>>> =
+    Tracked::new(Locked::new(GrowableHeap::new()));
+
+/// A design for handing out heap memory. It is given one range of memory
+/// which only ever gets bigger, from the end, until `init` starts it over.
+pub trait HeapAllocator {
+    /// An allocator with no memory, until `init` is called.
+    const EMPTY: Self;
+
+    /// Hand out the `size` bytes starting at `start`, forgetting everything
+    /// that was handed out before.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// memory is mapped and not used by anything else.
+    /// # Safety
+    ///    There is none. Must follow the rules.
+    unsafe fn init(&mut self, start: usize, size: usize);
+
+    /// Add the `by` bytes just past the end of the heap to it.
+    ///
+    /// This function is unsafe for the same reasons as `init`.
+    /// # Safety
+    ///    There is none. Must follow the rules.
+    unsafe fn extend(&mut self, by: usize);
+
+    /// Find memory for `layout`, or return null if there isn't room.
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// Give back memory returned by `allocate` with the same `layout`.
+    /// # Safety
+    ///    There is none. Must follow the rules.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+
+    /// How many bytes the heap covers.
+    fn size(&self) -> usize;
+
+    /// How many bytes are allocated, including any the design wastes on
+    /// rounding up. Zero once everything has been given back.
+    fn used(&self) -> usize;
+}
+
+/// A wrapper around spin::Mutex so we can implement traits like GlobalAlloc
+/// on locked allocators.
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+// Initializing the Kernel's heap. We need to do this, of course, so we can
+// store data for the Kernel itself on a heap. Only the first HEAP_SIZE bytes
+// are mapped up front, the rest of the reserved range is mapped as the heap
+// grows.
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    memory::with_mapper(|mapper| -> Result<(), MapToError<Size4KiB>> {
+        // Create the page table entries for the initial heap pages
+        for page in pages(HEAP_START, HEAP_SIZE) {
+            let frame: PhysFrame = GlobalFrameAllocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags, &mut GlobalFrameAllocator)?
+                    .flush()
+            };
+        }
+        Ok(())
+    })?;
+
+    // Initialize our heap allocator to the correct heap location
+    // after we have initialized the page table
+    unsafe {
+        ALLOCATOR.inner().lock().heap.init(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+/// Let the heap grow to at most `limit` bytes, capped at HEAP_RESERVED.
+/// A heap that is already bigger stays that size.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.min(HEAP_RESERVED), Ordering::Relaxed);
+}
+
+/// How big the heap is allowed to grow.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// How many bytes of the heap are currently mapped.
+pub fn heap_size() -> usize {
+    interrupts::without_interrupts(|| ALLOCATOR.inner().lock().heap.size())
+}
+
+/// Unmap the heap back down to HEAP_SIZE and return how many bytes were
+/// released. None of the designs can release the end of the heap while
+/// anything is allocated from it, so otherwise this does nothing.
+pub fn shrink_heap() -> usize {
+    interrupts::without_interrupts(|| ALLOCATOR.inner().lock().shrink())
+}
+
+// Called when an allocation fails and the code that wanted it has no way to
+// cope, eg. `Box::new`. Say what didn't fit and where it came from rather
+// than just stopping. Code that can carry on without the memory should use
+// `try_box` or `try_vec_with_capacity` instead.
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    let stats = stats::stats();
+    report(format_args!(
+        "Out of memory allocating {} bytes aligned to {}",
+        layout.size(),
+        layout.align()
+    ));
+    report(format_args!(
+        "Heap ({}): {} bytes live in {} allocations, {} of {} bytes mapped, peak {}",
+        BACKEND,
+        stats.live_bytes,
+        stats.live_allocations(),
+        stats.heap_size,
+        heap_limit(),
+        stats.peak_bytes
+    ));
+    crate::backtrace::backtrace();
+    // Through the panic handler so a test that runs out of memory fails
+    // instead of hanging
+    panic!("out of memory");
+}
+
+/// Print a line to VGA and serial.
+fn report(args: fmt::Arguments) {
+    println!("{}", args);
+    serial_println!("{}", args);
+}
+
+/// An allocation failed because the heap is out of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError {
+    /// What couldn't be allocated.
+    pub layout: Layout,
+}
+
+/// Like `Box::new` but returns an error if the heap is out of memory.
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+    let ptr = unsafe { alloc::alloc::alloc(layout) } as *mut T;
+    if ptr.is_null() {
+        return Err(AllocError { layout });
+    }
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Like `Vec::with_capacity` but returns an error if the heap is out of
+/// memory.
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(capacity).map_err(|_| AllocError {
+        // Too big to even describe is the same as too big to fit
+        layout: Layout::array::<T>(capacity).unwrap_or(Layout::new::<T>()),
+    })?;
+    Ok(vec)
+}
+
+/// A heap that maps more memory when it runs out.
+pub struct GrowableHeap<H> {
+    heap: H,
+}
+
+impl<H: HeapAllocator> GrowableHeap<H> {
+    pub const fn new() -> Self {
+        GrowableHeap { heap: H::EMPTY }
+    }
+
+    /// Map at least `bytes` more memory onto the end of the heap. Returns
+    /// false if that would go over the limit or there is no memory left.
+    fn grow(&mut self, bytes: usize) -> bool {
+        let size = self.heap.size();
+        let limit = HEAP_LIMIT.load(Ordering::Relaxed);
+        let wanted = align_up(bytes.max(HEAP_GROWTH), PAGE_SIZE);
+        let by = wanted.min(limit.saturating_sub(size) / PAGE_SIZE * PAGE_SIZE);
+        if by < bytes {
+            return false;
+        }
+        // Waiting on the mapper could deadlock if the allocation came from
+        // code that is holding it
+        let top = HEAP_START + size;
+        let Some(mapped) = memory::try_with_mapper(|mapper| map_pages(mapper, top, by)) else {
+            return false;
+        };
+        if mapped > 0 {
+            unsafe { self.heap.extend(mapped) };
+        }
+        mapped >= bytes
+    }
+
+    fn shrink(&mut self) -> usize {
+        let size = self.heap.size();
+        if self.heap.used() != 0 || size <= HEAP_SIZE {
+            return 0;
+        }
+        let released = memory::try_with_mapper(|mapper| {
+            for page in pages(HEAP_START + HEAP_SIZE, size - HEAP_SIZE) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                }
+            }
+        });
+        if released.is_none() {
+            return 0;
+        }
+        unsafe { self.heap.init(HEAP_START, HEAP_SIZE) };
+        size - HEAP_SIZE
+    }
+}
+
+// Interrupts are off while the heap is locked. A thread preempted holding
+// the lock would otherwise leave anyone allocating with interrupts off
+// spinning forever, see `thread`.
+unsafe impl<H: HeapAllocator> GlobalAlloc for Locked<GrowableHeap<H>> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            let ptr = allocator.heap.allocate(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+            // The new memory may not start where the alignment wants it to
+            if allocator.grow(layout.size() + layout.align()) {
+                return allocator.heap.allocate(layout);
+            }
+            ptr::null_mut()
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.lock().heap.deallocate(ptr, layout))
+    }
+}
+
+/// Map fresh frames for `len` bytes starting at `start`, stopping at the
+/// first failure. Returns how many bytes were mapped.
+fn map_pages(mapper: &mut OffsetPageTable, start: usize, len: usize) -> usize {
+    let mut mapped = 0;
+    for page in pages(start, len) {
+        let Some(frame) = GlobalFrameAllocator.allocate_frame() else {
+            break;
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                break;
+            }
+        }
+        mapped += PAGE_SIZE;
+    }
+    mapped
+}
+
+/// The pages covering `len` bytes starting at `start`.
+fn pages(start: usize, len: usize) -> impl Iterator<Item = Page<Size4KiB>> {
+    let start = VirtAddr::new(start as u64);
+    let end = start + (len - 1) as u64;
+    Page::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(end),
+    )
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// The designs lay out the same allocations differently so each has its own
+// idea of where the boxes should end up.
+#[test_case]
+#[cfg(not(any(feature = "bump-allocator", feature = "slab-allocator")))]
+fn heap_allocation_and_freeing() {
+    use alloc::boxed::Box;
+    use alloc::format;
+    // Start of address space
+    let heap = Box::new(41);
+    assert_eq!(format!("{:p}", heap), format!("0x{:x}", HEAP_START));
+    // Next 16 bytes
+    let heap = Box::new(42);
+    assert_eq!(format!("{:p}", heap), format!("0x{:x}", HEAP_START + 16));
+    // Free up 16 bytes
+    drop(heap);
+    // Reuse previous 16 bytes
+    let heap = Box::new(42);
+    assert_eq!(format!("{:p}", heap), format!("0x{:x}", HEAP_START + 16));
+}
+
+#[test_case]
+#[cfg(feature = "bump-allocator")]
+fn heap_allocation_and_freeing() {
+    use alloc::boxed::Box;
+    use alloc::format;
+    // Start of address space
+    let heap = Box::new(41);
+    assert_eq!(format!("{:p}", heap), format!("0x{:x}", HEAP_START));
+    // Right after it
+    let heap = Box::new(42);
+    assert_eq!(format!("{:p}", heap), format!("0x{:x}", HEAP_START + 4));
+    drop(heap);
+    // Nothing is reused until everything has been freed
+    let heap = Box::new(42);
+    assert_eq!(format!("{:p}", heap), format!("0x{:x}", HEAP_START + 8));
+}
+
+#[test_case]
+#[cfg(feature = "slab-allocator")]
+fn heap_allocation_and_freeing() {
+    use alloc::boxed::Box;
+    use alloc::format;
+    // The first object of the first slab, after its header
+    let heap = Box::new(41);
+    assert_eq!(format!("{:p}", heap), format!("0x{:x}", HEAP_START + 24));
+    // The next 8 byte object
+    let heap = Box::new(42);
+    assert_eq!(format!("{:p}", heap), format!("0x{:x}", HEAP_START + 32));
+    drop(heap);
+    // Reuse the freed object
+    let heap = Box::new(42);
+    assert_eq!(format!("{:p}", heap), format!("0x{:x}", HEAP_START + 32));
+}
+
+#[test_case]
+fn test_fallible_allocation() {
+    let boxed = try_box(42u64).unwrap();
+    assert_eq!(*boxed, 42);
+    let vec = try_vec_with_capacity::<u64>(100).unwrap();
+    assert!(vec.capacity() >= 100);
+    // Way past the heap limit
+    let too_big = heap_limit() * 2;
+    assert_eq!(
+        try_vec_with_capacity::<u8>(too_big)
+            .unwrap_err()
+            .layout
+            .size(),
+        too_big
+    );
+}
+
+/// Allocate a few different layouts from a fresh `H` and check they are
+/// aligned, don't overlap, and are all given back.
+#[cfg(test)]
+fn check_heap_allocator<H: HeapAllocator>() {
+    let region_layout = Layout::from_size_align(8 * PAGE_SIZE, PAGE_SIZE).unwrap();
+    let region = unsafe { alloc::alloc::alloc(region_layout) } as usize;
+    assert_ne!(region, 0);
+    let mut heap = H::EMPTY;
+    unsafe {
+        heap.init(region, 4 * PAGE_SIZE);
+        heap.extend(4 * PAGE_SIZE);
+    }
+    assert_eq!(heap.size(), 8 * PAGE_SIZE);
+
+    let layouts = [(8, 8), (24, 8), (100, 4), (512, 64), (3000, 8)]
+        .map(|(size, align)| Layout::from_size_align(size, align).unwrap());
+    let allocations = layouts.map(|layout| {
+        let ptr = heap.allocate(layout);
+        assert!(!ptr.is_null());
+        assert!((ptr as usize).is_multiple_of(layout.align()));
+        assert!(ptr as usize >= region && ptr as usize + layout.size() <= region + heap.size());
+        (ptr as usize, layout)
+    });
+    for (i, &(a, a_layout)) in allocations.iter().enumerate() {
+        for &(b, b_layout) in &allocations[i + 1..] {
+            assert!(b >= a + a_layout.size() || a >= b + b_layout.size());
+        }
+    }
+    assert!(heap.used() > 0);
+    for (ptr, layout) in allocations {
+        unsafe { heap.deallocate(ptr as *mut u8, layout) };
+    }
+    assert_eq!(heap.used(), 0);
+    unsafe { alloc::alloc::dealloc(region as *mut u8, region_layout) };
+}
+
+#[test_case]
+fn test_every_heap_allocator() {
+    check_heap_allocator::<bump::BumpAllocator>();
+    check_heap_allocator::<fixed_size_block::FixedSizeBlockAllocator>();
+    check_heap_allocator::<linked_list::LinkedListAllocator>();
+    check_heap_allocator::<slab::SlabAllocator>();
+}
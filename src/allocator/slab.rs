@@ -0,0 +1,180 @@
+// Slab Allocator
+// Small objects are packed into slabs: pages holding objects of a single
+// size class, each slab with its own list of free objects and a count of the
+// ones in use. Like the fixed size block allocator allocating is popping a
+// list, but a slab goes back to the page heap as soon as its last object is
+// freed so memory freed in one size class can be used by another.
+//   see: https://en.wikipedia.org/wiki/Slab_allocation
+// Slabs are page aligned so the slab an object belongs to is found by
+// rounding its address down. Slabs and allocations too big for one come from
+// a linked list heap.
+use super::{align_up, HeapAllocator};
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+
+const SLAB_SIZE: usize = 4096;
+/// The object sizes. An object is also aligned to its size so these must be
+/// powers of two.
+const OBJECT_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024];
+
+/// Sits at the start of every slab, its objects follow.
+struct Slab {
+    /// The next slab of the same size class, or null.
+    next: *mut Slab,
+    /// The first free object, or null if the slab is full.
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+/// A free object, pointing at the next free object in its slab.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+pub struct SlabAllocator {
+    /// The first slab of each size class, or null.
+    slabs: [*mut Slab; OBJECT_SIZES.len()],
+    pages: Heap,
+    /// Bytes handed out, counting whole objects.
+    used: usize,
+}
+
+// The slabs are only reached through the allocator
+unsafe impl Send for SlabAllocator {}
+
+fn slab_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
+
+/// The index of the smallest object size `layout` fits in, if any.
+fn class_index(layout: &Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    OBJECT_SIZES.iter().position(|&size| size >= required)
+}
+
+impl SlabAllocator {
+    unsafe fn allocate_object(&mut self, class: usize) -> *mut u8 {
+        let mut slab = self.slabs[class];
+        while !slab.is_null() && (*slab).free.is_null() {
+            slab = (*slab).next;
+        }
+        if slab.is_null() {
+            slab = self.new_slab(class);
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+        }
+        let object = (*slab).free;
+        (*slab).free = (*object).next;
+        (*slab).in_use += 1;
+        object.cast()
+    }
+
+    /// Take a page for a new slab of `class` and put it at the front of its
+    /// list, or return null if there are no pages left.
+    unsafe fn new_slab(&mut self, class: usize) -> *mut Slab {
+        let Ok(page) = self.pages.allocate_first_fit(slab_layout()) else {
+            return ptr::null_mut();
+        };
+        let page = page.as_ptr();
+        let size = OBJECT_SIZES[class];
+        // Thread the free list backwards so the lowest object is used first
+        let mut free = ptr::null_mut();
+        let first = align_up(size_of::<Slab>(), size);
+        for offset in (first..SLAB_SIZE - size + 1).step_by(size).rev() {
+            let object: *mut FreeObject = page.add(offset).cast();
+            object.write(FreeObject { next: free });
+            free = object;
+        }
+        let slab: *mut Slab = page.cast();
+        slab.write(Slab {
+            next: self.slabs[class],
+            free,
+            in_use: 0,
+        });
+        self.slabs[class] = slab;
+        slab
+    }
+
+    unsafe fn deallocate_object(&mut self, ptr: *mut u8, class: usize) {
+        let slab = (ptr as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        let object: *mut FreeObject = ptr.cast();
+        object.write(FreeObject { next: (*slab).free });
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        if (*slab).in_use == 0 {
+            self.release_slab(class, slab);
+        }
+    }
+
+    /// Give an empty slab's page back. The last slab of a class is kept so
+    /// allocating and freeing one object over and over doesn't churn pages.
+    unsafe fn release_slab(&mut self, class: usize, slab: *mut Slab) {
+        if self.slabs[class] == slab && (*slab).next.is_null() {
+            return;
+        }
+        let mut link: *mut *mut Slab = &mut self.slabs[class];
+        while *link != slab {
+            link = &mut (**link).next;
+        }
+        *link = (*slab).next;
+        self.pages
+            .deallocate(NonNull::new_unchecked(slab.cast()), slab_layout());
+    }
+}
+
+impl HeapAllocator for SlabAllocator {
+    const EMPTY: Self = SlabAllocator {
+        slabs: [ptr::null_mut(); OBJECT_SIZES.len()],
+        pages: Heap::empty(),
+        used: 0,
+    };
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        *self = Self::EMPTY;
+        self.pages.init(start, size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.pages.extend(by);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (ptr, size) = match class_index(&layout) {
+            Some(class) => (unsafe { self.allocate_object(class) }, OBJECT_SIZES[class]),
+            None => (
+                self.pages
+                    .allocate_first_fit(layout)
+                    .map_or(ptr::null_mut(), NonNull::as_ptr),
+                layout.size(),
+            ),
+        };
+        if !ptr.is_null() {
+            self.used += size;
+        }
+        ptr
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match class_index(&layout) {
+            Some(class) => {
+                self.deallocate_object(ptr, class);
+                self.used -= OBJECT_SIZES[class];
+            }
+            None => {
+                self.pages.deallocate(NonNull::new_unchecked(ptr), layout);
+                self.used -= layout.size();
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.pages.size()
+    }
+
+    fn used(&self) -> usize {
+        self.used
+    }
+}
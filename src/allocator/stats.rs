@@ -0,0 +1,380 @@
+// Heap Statistics
+// Every allocation goes through `Tracked` on its way to the heap so we can
+// keep count of what is in use: live and peak bytes, how many allocations
+// there have been of each size, and how full each page of the heap is.
+// Counting is all atomics so it costs next to nothing and is always on.
+// To hunt down a leak turn on callsite tracking with `track_callsites`. Each
+// allocation then also records a few return addresses from the stack, see
+// `backtrace`, and `dump` lists the outstanding allocations grouped by the
+// code that made them, biggest first. Records go in a fixed size table since
+// the allocator can hardly allocate to keep track of itself, allocations
+// made while it is full are counted but not recorded.
+// `dump` prints everything to serial along with a map of the heap showing
+// how full each page is, so fragmentation can be seen at a glance.
+use super::{heap_size, DEFAULT_HEAP_LIMIT, HEAP_START, PAGE_SIZE};
+use crate::backtrace::{self, Frames};
+use crate::{serial_print, serial_println};
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Allocations are counted in power of two size classes from 8 bytes up to
+/// 64 KiB, with a last class for everything bigger.
+pub const SIZE_CLASSES: usize = 15;
+const SMALLEST_CLASS_SHIFT: u32 = 3;
+/// How many outstanding allocations callsite tracking can remember.
+const MAX_TRACKED: usize = 1024;
+/// Return addresses recorded for each tracked allocation.
+const TRACKED_FRAMES: usize = 10;
+/// How many different callsites `dump` can group allocations by.
+const MAX_CALLSITES: usize = 32;
+/// Pages of the heap the map covers. The heap can be allowed to grow past
+/// this but the map won't show it.
+const MAP_PAGES: usize = DEFAULT_HEAP_LIMIT / PAGE_SIZE;
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static CLASS_ALLOCATIONS: [AtomicU64; SIZE_CLASSES] = [const { AtomicU64::new(0) }; SIZE_CLASSES];
+static CLASS_LIVE: [AtomicU64; SIZE_CLASSES] = [const { AtomicU64::new(0) }; SIZE_CLASSES];
+/// Bytes in use on each page of the heap.
+static PAGE_BYTES: [AtomicU16; MAP_PAGES] = [const { AtomicU16::new(0) }; MAP_PAGES];
+
+static TRACK_CALLSITES: AtomicBool = AtomicBool::new(false);
+static UNTRACKED: AtomicU64 = AtomicU64::new(0);
+// Only locked with interrupts disabled
+static TRACKED: Mutex<[Option<Outstanding>; MAX_TRACKED]> = Mutex::new([None; MAX_TRACKED]);
+
+/// An allocation made while callsite tracking was on.
+#[derive(Clone, Copy)]
+struct Outstanding {
+    ptr: usize,
+    size: usize,
+    frames: [u64; TRACKED_FRAMES],
+}
+
+/// A snapshot of the heap's statistics.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes mapped for the heap.
+    pub heap_size: usize,
+    /// Bytes requested by allocations that haven't been freed.
+    pub live_bytes: usize,
+    /// The most `live_bytes` has ever been.
+    pub peak_bytes: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub size_classes: [SizeClassStats; SIZE_CLASSES],
+}
+
+impl HeapStats {
+    pub fn live_allocations(&self) -> u64 {
+        self.allocations - self.deallocations
+    }
+}
+
+/// Allocations of at most `max_size` bytes, and more than the class before.
+#[derive(Debug, Clone, Copy)]
+pub struct SizeClassStats {
+    /// `usize::MAX` for the last class.
+    pub max_size: usize,
+    pub allocations: u64,
+    pub live: u64,
+}
+
+/// Counts every allocation made through the wrapped allocator.
+pub struct Tracked<A> {
+    inner: A,
+}
+
+impl<A> Tracked<A> {
+    pub const fn new(inner: A) -> Self {
+        Tracked { inner }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Tracked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            record_allocation(ptr as usize, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record_deallocation(ptr as usize, layout.size());
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+/// The heap's statistics right now.
+pub fn stats() -> HeapStats {
+    let mut size_classes = [SizeClassStats {
+        max_size: 0,
+        allocations: 0,
+        live: 0,
+    }; SIZE_CLASSES];
+    for (class, stats) in size_classes.iter_mut().enumerate() {
+        stats.max_size = class_max_size(class);
+        stats.allocations = CLASS_ALLOCATIONS[class].load(Ordering::Relaxed);
+        stats.live = CLASS_LIVE[class].load(Ordering::Relaxed);
+    }
+    HeapStats {
+        heap_size: heap_size(),
+        live_bytes: LIVE_BYTES.load(Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        size_classes,
+    }
+}
+
+/// Start or stop recording where allocations are made. Turning it off
+/// forgets everything recorded so far.
+pub fn track_callsites(enabled: bool) {
+    TRACK_CALLSITES.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        interrupts::without_interrupts(|| TRACKED.lock().fill(None));
+        UNTRACKED.store(0, Ordering::Relaxed);
+    }
+}
+
+fn size_class(size: usize) -> usize {
+    let bits = size.max(1).next_power_of_two().trailing_zeros();
+    (bits.saturating_sub(SMALLEST_CLASS_SHIFT) as usize).min(SIZE_CLASSES - 1)
+}
+
+fn class_max_size(class: usize) -> usize {
+    if class == SIZE_CLASSES - 1 {
+        usize::MAX
+    } else {
+        1 << (class as u32 + SMALLEST_CLASS_SHIFT)
+    }
+}
+
+fn record_allocation(ptr: usize, size: usize) {
+    let live = LIVE_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_BYTES.fetch_max(live, Ordering::Relaxed);
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    let class = size_class(size);
+    CLASS_ALLOCATIONS[class].fetch_add(1, Ordering::Relaxed);
+    CLASS_LIVE[class].fetch_add(1, Ordering::Relaxed);
+    for_each_page(ptr, size, |page, bytes| {
+        page.fetch_add(bytes, Ordering::Relaxed);
+    });
+
+    if TRACK_CALLSITES.load(Ordering::Relaxed) {
+        let mut frames = [0; TRACKED_FRAMES];
+        for (slot, frame) in frames.iter_mut().zip(Frames::current()) {
+            *slot = frame;
+        }
+        let record = Outstanding { ptr, size, frames };
+        let stored = interrupts::without_interrupts(|| {
+            let mut tracked = TRACKED.lock();
+            let slot = tracked.iter_mut().find(|slot| slot.is_none());
+            slot.map(|slot| *slot = Some(record)).is_some()
+        });
+        if !stored {
+            UNTRACKED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn record_deallocation(ptr: usize, size: usize) {
+    LIVE_BYTES.fetch_sub(size, Ordering::Relaxed);
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    CLASS_LIVE[size_class(size)].fetch_sub(1, Ordering::Relaxed);
+    for_each_page(ptr, size, |page, bytes| {
+        page.fetch_sub(bytes, Ordering::Relaxed);
+    });
+
+    if TRACK_CALLSITES.load(Ordering::Relaxed) {
+        interrupts::without_interrupts(|| {
+            let mut tracked = TRACKED.lock();
+            if let Some(slot) = tracked
+                .iter_mut()
+                .find(|slot| slot.is_some_and(|record| record.ptr == ptr))
+            {
+                *slot = None;
+            }
+        });
+    }
+}
+
+/// Call `f` with each page of the map `size` bytes at `ptr` cover and how
+/// many of those bytes are on it.
+fn for_each_page(ptr: usize, size: usize, mut f: impl FnMut(&AtomicU16, u16)) {
+    let Some(mut offset) = ptr.checked_sub(HEAP_START) else {
+        return;
+    };
+    let end = offset + size;
+    while offset < end {
+        let page_end = (offset / PAGE_SIZE + 1) * PAGE_SIZE;
+        let Some(page) = PAGE_BYTES.get(offset / PAGE_SIZE) else {
+            return;
+        };
+        f(page, (page_end.min(end) - offset) as u16);
+        offset = page_end;
+    }
+}
+
+/// Print the heap's statistics, outstanding allocations by callsite if
+/// tracking is on, and a map of the heap to serial.
+pub fn dump() {
+    let stats = stats();
+    serial_println!(
+        "Heap ({}): {} of {} bytes live, peak {}, {} allocations, {} live",
+        super::BACKEND,
+        stats.live_bytes,
+        stats.heap_size,
+        stats.peak_bytes,
+        stats.allocations,
+        stats.live_allocations()
+    );
+    serial_println!("  size class    allocations        live");
+    for class in stats.size_classes.iter().filter(|c| c.allocations > 0) {
+        let (bound, size) = match class.max_size {
+            usize::MAX => (">", class_max_size(SIZE_CLASSES - 2)),
+            size => ("<=", size),
+        };
+        serial_println!(
+            "      {:>2} {:>5} {:>12} {:>11}",
+            bound,
+            size,
+            class.allocations,
+            class.live
+        );
+    }
+    if TRACK_CALLSITES.load(Ordering::Relaxed) {
+        dump_callsites();
+    }
+    dump_map(stats.heap_size);
+}
+
+/// Group the tracked allocations by where they were made.
+fn dump_callsites() {
+    // (callsite, allocations, bytes)
+    let mut callsites = [(0u64, 0usize, 0usize); MAX_CALLSITES];
+    let mut other = (0, 0);
+    for i in 0..MAX_TRACKED {
+        // Copy records out one at a time to keep interrupts off for as short
+        // as we can, the table is too big to copy onto the stack
+        let Some(record) = interrupts::without_interrupts(|| TRACKED.lock()[i]) else {
+            continue;
+        };
+        let callsite = callsite(&record.frames);
+        match callsites
+            .iter_mut()
+            .find(|(addr, count, _)| *addr == callsite || *count == 0)
+        {
+            Some(entry) => {
+                *entry = (callsite, entry.1 + 1, entry.2 + record.size);
+            }
+            None => other = (other.0 + 1, other.1 + record.size),
+        }
+    }
+    callsites.sort_unstable_by_key(|&(_, _, bytes)| core::cmp::Reverse(bytes));
+
+    serial_println!("Outstanding allocations by callsite:");
+    for &(addr, count, bytes) in callsites.iter().filter(|(_, count, _)| *count > 0) {
+        serial_print!("{:>10} bytes in {:>5}: {:#018x} ", bytes, count, addr);
+        // Zero when not a single frame was recorded
+        match addr.checked_sub(1).and_then(backtrace::symbolize) {
+            Some((name, offset)) => {
+                serial_println!("{}+{:#x}", name, offset + 1);
+            }
+            None => {
+                serial_println!("<unknown>");
+            }
+        }
+    }
+    if other.0 > 0 {
+        serial_println!("{:>10} bytes in {:>5}: elsewhere", other.1, other.0);
+    }
+    let untracked = UNTRACKED.load(Ordering::Relaxed);
+    if untracked > 0 {
+        serial_println!(
+            "({} allocations not tracked, the table was full)",
+            untracked
+        );
+    }
+}
+
+/// The first return address outside of the allocator and the `alloc` crate,
+/// which is the code that asked for the memory. Without a symbol table we
+/// can't tell so settle for the innermost.
+fn callsite(frames: &[u64; TRACKED_FRAMES]) -> u64 {
+    let is_allocator = |name: &str| {
+        [
+            "alloc::",
+            "<alloc::",
+            "core::",
+            "<core::",
+            "__rust",
+            "__rg_",
+            "greg_os::allocator",
+            "<greg_os::allocator",
+        ]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+    };
+    frames
+        .iter()
+        .take_while(|&&frame| frame != 0)
+        .find(|&&frame| {
+            backtrace::symbolize(frame - 1).is_some_and(|(name, _)| !is_allocator(name))
+        })
+        .copied()
+        .unwrap_or(frames[0])
+}
+
+/// One character per page of the heap:
+///   ` ` nothing, `.` up to a quarter used, `o` up to three quarters,
+///   `O` less than full, `#` full
+fn dump_map(heap_size: usize) {
+    const PAGES_PER_ROW: usize = 64;
+    let pages = (heap_size / PAGE_SIZE).min(MAP_PAGES);
+    serial_println!("Heap map, one character per {} byte page:", PAGE_SIZE);
+    for row in 0..pages.div_ceil(PAGES_PER_ROW) {
+        let mut line = [b' '; PAGES_PER_ROW];
+        let first = row * PAGES_PER_ROW;
+        for (i, c) in line.iter_mut().enumerate().take(pages - first) {
+            let used = PAGE_BYTES[first + i].load(Ordering::Relaxed) as usize;
+            *c = match used {
+                0 => b' ',
+                used if used <= PAGE_SIZE / 4 => b'.',
+                used if used <= PAGE_SIZE * 3 / 4 => b'o',
+                used if used < PAGE_SIZE => b'O',
+                _ => b'#',
+            };
+        }
+        let line = core::str::from_utf8(&line).unwrap_or("");
+        serial_println!("{:#x} |{}|", HEAP_START + first * PAGE_SIZE, line);
+    }
+}
+
+#[test_case]
+fn test_stats_count_allocations() {
+    use alloc::boxed::Box;
+    let before = stats();
+    let boxed = Box::new([0u8; 100]);
+    let during = stats();
+    assert_eq!(during.live_bytes, before.live_bytes + 100);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak_bytes >= during.live_bytes);
+    let class = size_class(100);
+    assert_eq!(class_max_size(class), 128);
+    assert_eq!(
+        during.size_classes[class].live,
+        before.size_classes[class].live + 1
+    );
+    drop(boxed);
+    assert_eq!(stats().live_bytes, before.live_bytes);
+}
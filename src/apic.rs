@@ -0,0 +1,348 @@
+// Advanced Programmable Interrupt Controller
+// The 8259 PICs only know about 16 IRQs and one CPU. Modern machines instead
+// have a Local APIC in every CPU, which receives interrupts and has its own
+// timer, and one or more I/O APICs which take the device interrupt lines and
+// route them to a Local APIC as an IDT vector of our choosing.
+//   see: https://wiki.osdev.org/APIC
+//   see: https://wiki.osdev.org/IOAPIC
+//   see: https://wiki.osdev.org/APIC_timer
+// CPUID tells us if there is a Local APIC and whether it supports x2APIC mode,
+// where its registers are accessed through MSRs instead of memory mapped I/O.
+// The ACPI MADT tells us where the I/O APICs are and how ISA IRQs map to
+// their inputs (Global System Interrupts).
+// Once the APICs are set up we mask the legacy PICs and every end of
+// interrupt goes to the Local APIC instead. If anything is missing we leave
+// the PICs alone and keep using them.
+
+use crate::acpi::{self, ArrayVec, Madt, MAX_IO_APICS};
+use crate::interrupts::{self, InterruptIndex, IRQ_COUNT, PICS};
+use crate::{memory, pit};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Virtual address where the APIC registers get mapped. The Local APIC gets
+/// the first page and each I/O APIC gets a page after it.
+pub const APIC_MMIO_START: u64 = 0x_4444_5555_0000;
+/// A page for the local APIC followed by one for each I/O APIC.
+pub const APIC_MMIO_SIZE: u64 = (1 + MAX_IO_APICS as u64) * 4096;
+
+/// MSR holding the Local APIC's physical base address and enable bits.
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+/// In x2APIC mode register `offset` lives in MSR `0x800 + (offset >> 4)`.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Local APIC register offsets.
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+enum Register {
+    Id = 0x20,
+    EndOfInterrupt = 0xB0,
+    SpuriousVector = 0xF0,
+    LvtTimer = 0x320,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivide = 0x3E0,
+}
+
+/// Bit in the spurious vector register that software enables the Local APIC.
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+/// Bit in a local vector table entry that masks it.
+const LVT_MASKED: u32 = 1 << 16;
+/// Bit in the timer's local vector table entry for periodic mode.
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Timer divide configuration value for dividing the bus clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+/// How many PIT ticks to measure the Local APIC timer against.
+const CALIBRATION_TICKS: u64 = 10;
+
+/// I/O APIC register select and data window offsets.
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+/// I/O APIC version register, bits 16-23 hold the number of inputs minus one.
+const IOAPICVER: u32 = 0x01;
+/// First I/O APIC redirection table register, each entry is two registers.
+const IOREDTBL: u32 = 0x10;
+/// Redirection entry bits.
+const REDIRECT_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECT_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECT_MASKED: u64 = 1 << 16;
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// CPUID says there is no Local APIC.
+    NotSupported,
+    /// The ACPI tables or the MADT could not be found.
+    NoMadt,
+    /// The MADT doesn't list any I/O APICs.
+    NoIoApic,
+    /// Mapping the registers into memory failed.
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for ApicError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        ApicError::Map(err)
+    }
+}
+
+/// How the Local APIC registers are accessed.
+#[derive(Debug, Clone, Copy)]
+enum LocalApic {
+    /// Memory mapped at the given virtual address.
+    XApic(VirtAddr),
+    /// Through MSRs.
+    X2Apic,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, reg: Register) -> u32 {
+        match self {
+            LocalApic::XApic(base) => {
+                core::ptr::read_volatile((*base + reg as u64).as_ptr::<u32>())
+            }
+            LocalApic::X2Apic => Msr::new(X2APIC_MSR_BASE + (reg as u32 >> 4)).read() as u32,
+        }
+    }
+
+    unsafe fn write(&self, reg: Register, value: u32) {
+        match self {
+            LocalApic::XApic(base) => {
+                core::ptr::write_volatile((*base + reg as u64).as_mut_ptr::<u32>(), value)
+            }
+            LocalApic::X2Apic => Msr::new(X2APIC_MSR_BASE + (reg as u32 >> 4)).write(value as u64),
+        }
+    }
+
+    fn id(&self) -> u32 {
+        let id = unsafe { self.read(Register::Id) };
+        match self {
+            // Only the top byte is the id in xAPIC mode
+            LocalApic::XApic(_) => id >> 24,
+            LocalApic::X2Apic => id,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    base: VirtAddr,
+    /// The first Global System Interrupt this I/O APIC handles.
+    gsi_base: u32,
+    /// How many inputs, and so redirection entries, this I/O APIC has.
+    inputs: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&mut self, reg: u32) -> u32 {
+        core::ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+        core::ptr::read_volatile((self.base + IOWIN).as_ptr::<u32>())
+    }
+
+    unsafe fn write(&mut self, reg: u32, value: u32) {
+        core::ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+        core::ptr::write_volatile((self.base + IOWIN).as_mut_ptr::<u32>(), value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.inputs
+    }
+
+    unsafe fn set_redirection(&mut self, gsi: u32, entry: u64) {
+        let reg = IOREDTBL + (gsi - self.gsi_base) * 2;
+        self.write(reg, entry as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+    }
+}
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+static MADT: OnceCell<Madt> = OnceCell::uninit();
+static IO_APICS: Mutex<ArrayVec<IoApic, MAX_IO_APICS>> = Mutex::new(ArrayVec::new());
+
+/// Whether interrupts are being delivered by the APICs rather than the PICs.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.is_initialized()
+}
+
+/// Check CPUID for a Local APIC and x2APIC support.
+/// Returns `(apic, x2apic)`.
+pub fn detect() -> (bool, bool) {
+    let features = crate::cpuid(1);
+    let apic = features.edx & (1 << 9) != 0;
+    let x2apic = features.ecx & (1 << 21) != 0;
+    (apic, x2apic)
+}
+
+/// Set up the Local and I/O APICs and switch interrupt delivery over to them.
+/// Must be called with interrupts enabled and the PIT running since the Local
+/// APIC timer is calibrated against it.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> Result<(), ApicError> {
+    let (has_apic, has_x2apic) = detect();
+    if !has_apic {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = unsafe { acpi::find_madt(physical_memory_offset) }.ok_or(ApicError::NoMadt)?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let local_apic = if has_x2apic {
+        // Going straight from disabled to x2APIC mode is an invalid
+        // transition and raises #GP, it has to be enabled in xAPIC mode first
+        unsafe {
+            let base = base_msr.read() | APIC_BASE_GLOBAL_ENABLE;
+            base_msr.write(base);
+            base_msr.write(base | APIC_BASE_X2APIC_ENABLE);
+        }
+        LocalApic::X2Apic
+    } else {
+        let page = Page::containing_address(VirtAddr::new(APIC_MMIO_START));
+        let frame = PhysFrame::containing_address(PhysAddr::new(madt.local_apic_address));
+        unsafe {
+            memory::map_mmio(page, frame, mapper, frame_allocator)?;
+            let base = base_msr.read();
+            base_msr.write(base | APIC_BASE_GLOBAL_ENABLE);
+        }
+        LocalApic::XApic(page.start_address())
+    };
+
+    let mut io_apics = ArrayVec::new();
+    for (i, info) in madt.io_apics.iter().enumerate() {
+        let page = Page::containing_address(VirtAddr::new(APIC_MMIO_START) + (i as u64 + 1) * 4096);
+        let frame = PhysFrame::containing_address(PhysAddr::new(info.address));
+        unsafe { memory::map_mmio(page, frame, mapper, frame_allocator)? };
+        let mut io_apic = IoApic {
+            base: page.start_address() + (info.address & 0xFFF),
+            gsi_base: info.gsi_base,
+            inputs: 0,
+        };
+        io_apic.inputs = ((unsafe { io_apic.read(IOAPICVER) } >> 16) & 0xFF) + 1;
+        // The firmware may have left entries enabled, mask them all until
+        // someone routes an IRQ
+        for input in 0..io_apic.inputs {
+            unsafe { io_apic.set_redirection(io_apic.gsi_base + input, REDIRECT_MASKED) };
+        }
+        io_apics.push(io_apic);
+    }
+
+    let timer_initial_count = calibrate_timer(&local_apic);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if madt.has_8259_pics {
+            unsafe { PICS.lock().disable() };
+        }
+        unsafe {
+            local_apic.write(
+                Register::SpuriousVector,
+                SPURIOUS_APIC_ENABLE | InterruptIndex::ApicSpurious as u32,
+            );
+        }
+        *IO_APICS.lock() = io_apics;
+        MADT.init_once(|| madt);
+        LOCAL_APIC.init_once(|| local_apic);
+
+        // Carry over every IRQ a driver registered while we were on the PICs.
+        // The Local APIC timer takes over from the PIT which stays masked.
+        for irq in 1..IRQ_COUNT as u8 {
+            if interrupts::is_registered(irq) {
+                interrupts::unmask_irq(irq);
+            }
+        }
+        unsafe {
+            local_apic.write(Register::TimerDivide, TIMER_DIVIDE_BY_16);
+            local_apic.write(
+                Register::LvtTimer,
+                LVT_TIMER_PERIODIC | InterruptIndex::Timer as u32,
+            );
+            local_apic.write(Register::TimerInitialCount, timer_initial_count);
+        }
+    });
+
+    Ok(())
+}
+
+/// Measure how many Local APIC timer counts happen per PIT tick so the Local
+/// APIC timer can tick at the same rate.
+fn calibrate_timer(local_apic: &LocalApic) -> u32 {
+    use x86_64::instructions::hlt;
+
+    unsafe {
+        local_apic.write(Register::TimerDivide, TIMER_DIVIDE_BY_16);
+        local_apic.write(Register::LvtTimer, LVT_MASKED);
+    }
+    // Line up with the start of a tick
+    let start = pit::ticks();
+    while pit::ticks() == start {
+        hlt();
+    }
+
+    unsafe { local_apic.write(Register::TimerInitialCount, u32::MAX) };
+    let start = pit::ticks();
+    while pit::ticks() < start + CALIBRATION_TICKS {
+        hlt();
+    }
+    let remaining = unsafe { local_apic.read(Register::TimerCurrentCount) };
+    // Stop the timer
+    unsafe { local_apic.write(Register::TimerInitialCount, 0) };
+
+    (u32::MAX - remaining) / CALIBRATION_TICKS as u32
+}
+
+/// Route a legacy ISA IRQ through the I/O APICs to `vector` on this CPU,
+/// honouring any interrupt source override from the MADT.
+/// Does nothing if the APICs aren't in use.
+pub fn route_isa_irq(irq: u8, vector: u8) {
+    let (Ok(madt), Ok(local_apic)) = (MADT.try_get(), LOCAL_APIC.try_get()) else {
+        return;
+    };
+    let (gsi, source_override) = madt.isa_irq_to_gsi(irq);
+
+    let mut entry = vector as u64 | (local_apic.id() as u64) << 56;
+    if let Some(source_override) = source_override {
+        if source_override.active_low() {
+            entry |= REDIRECT_ACTIVE_LOW;
+        }
+        if source_override.level_triggered() {
+            entry |= REDIRECT_LEVEL_TRIGGERED;
+        }
+    }
+    set_redirection(gsi, entry);
+}
+
+/// Stop a legacy ISA IRQ from being delivered by the I/O APICs.
+/// Does nothing if the APICs aren't in use.
+pub fn mask_isa_irq(irq: u8) {
+    let Ok(madt) = MADT.try_get() else {
+        return;
+    };
+    let (gsi, _) = madt.isa_irq_to_gsi(irq);
+    set_redirection(gsi, REDIRECT_MASKED);
+}
+
+fn set_redirection(gsi: u32, entry: u64) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for io_apic in IO_APICS.lock().iter_mut().filter(|io| io.handles(gsi)) {
+            unsafe { io_apic.set_redirection(gsi, entry) };
+        }
+    });
+}
+
+/// WARNING Called by interrupt handlers.
+/// WARNING Must not block or allocate.
+/// Signal the end of an interrupt to the Local APIC.
+pub fn end_of_interrupt() {
+    if let Ok(local_apic) = LOCAL_APIC.try_get() {
+        unsafe { local_apic.write(Register::EndOfInterrupt, 0) };
+    }
+}
@@ -0,0 +1,172 @@
+// Stack Backtraces
+// The kernel is built with frame pointers (see `x86_64-blog_os.json`) so every
+// function starts by pushing the caller's `rbp` and pointing `rbp` at it. That
+// makes the stack a linked list we can walk:
+//   [rbp]     -> the caller's saved rbp
+//   [rbp + 8] -> the return address into the caller
+// Turning return addresses into function names needs a symbol table. The
+// linker can't give us one from inside the kernel so `tools/runner.sh` fills
+// in `SYMBOL_TABLE` after linking, before `bootimage` packages the kernel. It
+// is plain text, one function per line sorted by address:
+//   "<16 hex digit address> <demangled name>\n"
+// and terminated by a NUL byte. Kernels that didn't go through the runner
+// just have an empty table and print bare addresses.
+
+use crate::{memory, println, serial_println};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::VirtAddr;
+
+/// Space reserved in the kernel image for the symbol table. A debug kernel's
+/// is about 750 KiB.
+const SYMBOL_TABLE_SIZE: usize = 1024 * 1024;
+/// Stop walking after this many frames in case the chain is corrupt.
+const MAX_FRAMES: usize = 64;
+
+/// Set once a backtrace has been printed, so the panic that usually follows
+/// a fatal exception or running out of memory doesn't print another.
+static PRINTED: AtomicBool = AtomicBool::new(false);
+
+/// Filled in after linking by `tools/runner.sh`.
+#[used]
+#[link_section = ".ksyms"]
+static SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+fn symbol_table() -> &'static str {
+    // As far as the compiler knows the table is all zeros, hide it so reads
+    // from it aren't constant folded away.
+    let table: &'static [u8] = core::hint::black_box(&SYMBOL_TABLE);
+    let len = table.iter().position(|&b| b == 0).unwrap_or(table.len());
+    core::str::from_utf8(&table[..len]).unwrap_or("")
+}
+
+/// Find the function containing `addr` and how far into it `addr` is.
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    let mut found = None;
+    for line in symbol_table().lines() {
+        let (Some(hex), Some(name)) = (line.get(..16), line.get(17..)) else {
+            continue;
+        };
+        let Ok(start) = u64::from_str_radix(hex, 16) else {
+            continue;
+        };
+        if start > addr {
+            break;
+        }
+        found = Some((name, addr - start));
+    }
+    found
+}
+
+/// Iterator over the return addresses on the stack, innermost first.
+pub struct Frames {
+    rbp: u64,
+    depth: usize,
+}
+
+impl Frames {
+    /// Walk the stack of the calling function.
+    #[inline(always)]
+    pub fn current() -> Self {
+        let rbp: u64;
+        unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp) };
+        Self::from_rbp(rbp)
+    }
+
+    /// Walk the stack starting from a saved frame pointer, eg. the `rbp` of
+    /// code interrupted by an exception.
+    pub fn from_rbp(rbp: u64) -> Self {
+        Frames { rbp, depth: 0 }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.rbp == 0
+            || !self.rbp.is_multiple_of(8)
+            || !is_mapped(self.rbp)
+            || self.depth >= MAX_FRAMES
+        {
+            return None;
+        }
+        let frame = self.rbp as *const u64;
+        let (caller_rbp, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            return None;
+        }
+        // The stack grows down so the caller's frame is always above ours,
+        // anything else means we've hit the end of the chain or garbage.
+        self.rbp = if caller_rbp > self.rbp { caller_rbp } else { 0 };
+        self.depth += 1;
+        Some(return_address)
+    }
+}
+
+/// Whether the saved rbp and return address at `rbp` can be read without
+/// faulting. A corrupt frame pointer is likely when something has gone wrong
+/// badly enough to want a backtrace. Walks the page tables without the
+/// mapper's lock, which whatever went wrong may be holding.
+fn is_mapped(rbp: u64) -> bool {
+    let offset = memory::physical_memory_offset();
+    // Too early to tell
+    if offset.as_u64() == 0 {
+        return false;
+    }
+    [rbp, rbp + 8].into_iter().all(|addr| {
+        VirtAddr::try_new(addr)
+            .is_ok_and(|addr| unsafe { memory::translate_addr(addr, offset) }.is_some())
+    })
+}
+
+/// Print a backtrace of the calling function to VGA and serial.
+#[inline(always)]
+pub fn backtrace() {
+    print_frames(None, Frames::current());
+}
+
+/// Like `backtrace` but for the panic handler, prints nothing if a backtrace
+/// was already printed on the way to the panic, eg. by `exceptions::fatal`.
+#[inline(always)]
+pub fn panic_backtrace() {
+    if !PRINTED.load(Ordering::Relaxed) {
+        backtrace();
+    }
+}
+
+/// Print a backtrace of code that was interrupted at `rip` with frame pointer
+/// `rbp` to VGA and serial.
+pub fn backtrace_from(rip: u64, rbp: u64) {
+    print_frames(Some(rip), Frames::from_rbp(rbp));
+}
+
+fn print_frames(rip: Option<u64>, frames: Frames) {
+    PRINTED.store(true, Ordering::Relaxed);
+    println!("Backtrace:");
+    serial_println!("Backtrace:");
+    // `rip` is the faulting instruction itself while the return addresses
+    // point just past a call, so look those up one byte back to stay inside
+    // the calling function.
+    let addresses = rip
+        .into_iter()
+        .map(|rip| (rip, rip))
+        .chain(frames.map(|ret| (ret, ret - 1)));
+    for (i, (addr, lookup)) in addresses.enumerate() {
+        match symbolize(lookup) {
+            Some((name, offset)) => {
+                let offset = offset + (addr - lookup);
+                println!("{:>3}: {:#018x} {}+{:#x}", i, addr, name, offset);
+                serial_println!("{:>3}: {:#018x} {}+{:#x}", i, addr, name, offset);
+            }
+            None => {
+                println!("{:>3}: {:#018x} <unknown>", i, addr);
+                serial_println!("{:>3}: {:#018x} <unknown>", i, addr);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_frames_walk_the_stack() {
+    assert!(Frames::current().count() > 0);
+}
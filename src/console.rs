@@ -0,0 +1,313 @@
+// Console
+// Interactive code like the resume viewer talks to the user through here
+// rather than the VGA buffer and keyboard directly. Normally that is still
+// the VGA text buffer and the PS/2 keyboard, but built with the
+// `serial-console` feature everything goes over COM1 instead so the kernel
+// can be driven from a host terminal with no screen at all:
+//   cargo run --features serial-console -- -serial stdio -display none
+// On serial the screen is drawn with ANSI escape sequences, emulating the VGA
+// writer: text always goes on the bottom row and scrolls the rest up. Keys
+// come back from the terminal as bytes and escape sequences which are decoded
+// into the same `DecodedKey`s the keyboard produces.
+//   see: https://en.wikipedia.org/wiki/ANSI_escape_code
+#![allow(clippy::new_without_default)]
+use crate::serial::{self, ComPort, SerialPort, SerialStream};
+use crate::task::keyboard::ScancodeStream;
+use crate::vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH};
+use core::{
+    fmt::{self, Write},
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::stream::{Stream, StreamExt};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+use x86_64::instructions::interrupts;
+
+/// Whether the console is on COM1 rather than the screen and keyboard.
+pub const SERIAL: bool = cfg!(feature = "serial-console");
+
+/// Column of the next character on the bottom row of the serial console,
+/// like the VGA writer's `column_position`.
+static SERIAL_COLUMN: AtomicUsize = AtomicUsize::new(0);
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    if !SERIAL {
+        vga_buffer::_print(args);
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut port = serial::port(ComPort::Com1).lock();
+        // Jump to the bottom row for the text and put the cursor back after
+        let column = SERIAL_COLUMN.load(Ordering::Relaxed);
+        write!(port, "\x1b7\x1b[{};{}H", BUFFER_HEIGHT, column + 1).unwrap();
+        Text(&mut port).write_fmt(args).unwrap();
+        port.write_str("\x1b8").unwrap();
+    });
+}
+
+/// Writes text to the terminal the way the VGA writer lays it out.
+struct Text<'a>(&'a mut SerialPort);
+
+impl Write for Text<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // Only the first byte of a UTF-8 character takes up a column
+            let starts_char = byte & 0xC0 != 0x80;
+            if byte == b'\n'
+                || (starts_char && SERIAL_COLUMN.load(Ordering::Relaxed) >= BUFFER_WIDTH)
+            {
+                // A terminal in raw mode needs the carriage return too
+                self.0.send(b'\r');
+                self.0.send(b'\n');
+                SERIAL_COLUMN.store(0, Ordering::Relaxed);
+            }
+            if byte != b'\n' {
+                self.0.send(byte);
+                if starts_char {
+                    SERIAL_COLUMN.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Send escape sequences that control the terminal rather than print.
+fn escape(args: fmt::Arguments) {
+    interrupts::without_interrupts(|| {
+        serial::port(ComPort::Com1).lock().write_fmt(args).unwrap();
+    });
+}
+
+pub fn clear_screen() {
+    if SERIAL {
+        // Keep scrolling within the rows the VGA buffer would have
+        escape(format_args!("\x1b[1;{}r\x1b[2J", BUFFER_HEIGHT));
+    } else {
+        vga_buffer::WRITER.lock().clear_screen();
+    }
+}
+
+pub fn enable_cursor() {
+    if SERIAL {
+        escape(format_args!("\x1b[?25h"));
+    } else {
+        vga_buffer::enable_cursor();
+    }
+}
+
+pub fn disable_cursor() {
+    if SERIAL {
+        escape(format_args!("\x1b[?25l"));
+    } else {
+        vga_buffer::disable_cursor();
+    }
+}
+
+/// Move the cursor to column `x` of row `y`, counting from zero.
+pub fn move_cursor(x: usize, y: usize) {
+    if SERIAL {
+        escape(format_args!("\x1b[{};{}H", y + 1, x + 1));
+    } else {
+        vga_buffer::move_cursor(x, y);
+    }
+}
+
+/// Scroll everything down a row and write `line` on the top row.
+pub fn pre_write_line(line: &str) {
+    if SERIAL {
+        // Reverse index at the top of the screen scrolls down
+        escape(format_args!("\x1b7\x1b[H\x1bM\x1b[2K{}\x1b8", line));
+    } else {
+        vga_buffer::WRITER.lock().pre_write_line(line);
+    }
+}
+
+pub fn print_logo() {
+    if !SERIAL {
+        vga_buffer::print_logo();
+        return;
+    }
+    // The same box the VGA logo draws from code page 437
+    const GREEN: &str = "\x1b[92m";
+    const YELLOW: &str = "\x1b[93m";
+    const MAGENTA: &str = "\x1b[35m";
+    let padding = BUFFER_WIDTH / 2 - 10 / 2;
+    escape(format_args!("{}", GREEN));
+    for line in [
+        "╔════════╗",
+        "║        ║",
+        "╢ Greg☺S ╟",
+        "║        ║",
+        "╚════════╝",
+    ] {
+        crate::println!("{:padding$}{}", "", line);
+    }
+    crate::print!("\n\n\n");
+    escape(format_args!("{}", YELLOW));
+    print_centered("< Press any key to continue >");
+    for _ in 0..9 {
+        crate::println!();
+    }
+    let time = alloc::format!("{:?}", crate::rtc::read_rtc());
+    escape(format_args!("{}", MAGENTA));
+    print_centered(&time[0..time.len() - 2]);
+    escape(format_args!("{}", YELLOW));
+}
+
+fn print_centered(s: &str) {
+    let padding = BUFFER_WIDTH / 2 - s.len() / 2;
+    crate::print!("{:padding$}{}", "", s);
+}
+
+/// The keys pressed on the console's keyboard, or typed into its terminal.
+pub struct KeyStream {
+    input: Input,
+}
+
+enum Input {
+    Keyboard(ScancodeStream, Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Serial(SerialStream, AnsiDecoder),
+    /// There was no room for the scancode queue, nothing is ever pressed.
+    None,
+}
+
+impl KeyStream {
+    /// Start taking input. Only one stream can be created.
+    pub fn new() -> Self {
+        let input = if SERIAL {
+            Input::Serial(SerialStream::new(ComPort::Com1), AnsiDecoder::new())
+        } else {
+            match ScancodeStream::try_new() {
+                Ok(scancodes) => Input::Keyboard(
+                    scancodes,
+                    Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore),
+                ),
+                Err(err) => {
+                    crate::warn!(
+                        "no keyboard input, the scancode queue didn't fit: {:?}",
+                        err
+                    );
+                    Input::None
+                }
+            }
+        };
+        KeyStream { input }
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        loop {
+            let key = match &mut self.input {
+                Input::Keyboard(scancodes, keyboard) => {
+                    let Some(scancode) = futures_util::ready!(scancodes.poll_next_unpin(cx)) else {
+                        return Poll::Ready(None);
+                    };
+                    match keyboard.add_byte(scancode) {
+                        Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+                        _ => None,
+                    }
+                }
+                Input::Serial(bytes, decoder) => {
+                    let Some(byte) = futures_util::ready!(bytes.poll_next_unpin(cx)) else {
+                        return Poll::Ready(None);
+                    };
+                    decoder.decode(byte, bytes.has_pending())
+                }
+                Input::None => return Poll::Pending,
+            };
+            if let Some(key) = key {
+                return Poll::Ready(Some(key));
+            }
+        }
+    }
+}
+
+/// Turns the bytes a terminal sends into keys. Arrow keys arrive as
+/// `ESC [ A` to `ESC [ D`, possibly with numeric parameters before the letter.
+struct AnsiDecoder {
+    state: AnsiState,
+}
+
+enum AnsiState {
+    Ground,
+    /// Seen an `ESC`
+    Escape,
+    /// Seen `ESC [`, the Control Sequence Introducer
+    Csi,
+}
+
+impl AnsiDecoder {
+    fn new() -> Self {
+        AnsiDecoder {
+            state: AnsiState::Ground,
+        }
+    }
+
+    /// Feed in the next `byte`. `more_pending` says whether more bytes have
+    /// already arrived: the escape key sends a lone `ESC` so one with nothing
+    /// following it is taken to be the key rather than a sequence.
+    fn decode(&mut self, byte: u8, more_pending: bool) -> Option<DecodedKey> {
+        match self.state {
+            AnsiState::Ground => match byte {
+                0x1B if more_pending => {
+                    self.state = AnsiState::Escape;
+                    None
+                }
+                b'\r' => Some(DecodedKey::Unicode('\n')),
+                // Terminals send DEL for backspace
+                0x7F => Some(DecodedKey::Unicode('\x08')),
+                byte if byte.is_ascii() => Some(DecodedKey::Unicode(byte as char)),
+                _ => None,
+            },
+            AnsiState::Escape => {
+                self.state = AnsiState::Ground;
+                match byte {
+                    b'[' => {
+                        self.state = AnsiState::Csi;
+                        None
+                    }
+                    // Alt with a key, drop the Alt
+                    byte => self.decode(byte, more_pending),
+                }
+            }
+            AnsiState::Csi => {
+                if byte.is_ascii_digit() || byte == b';' {
+                    return None;
+                }
+                self.state = AnsiState::Ground;
+                let key = match byte {
+                    b'A' => KeyCode::ArrowUp,
+                    b'B' => KeyCode::ArrowDown,
+                    b'C' => KeyCode::ArrowRight,
+                    b'D' => KeyCode::ArrowLeft,
+                    _ => return None,
+                };
+                Some(DecodedKey::RawKey(key))
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_ansi_decoder() {
+    let mut decoder = AnsiDecoder::new();
+    assert_eq!(decoder.decode(b'a', false), Some(DecodedKey::Unicode('a')));
+    // An arrow key
+    assert_eq!(decoder.decode(0x1B, true), None);
+    assert_eq!(decoder.decode(b'[', true), None);
+    assert_eq!(
+        decoder.decode(b'B', false),
+        Some(DecodedKey::RawKey(KeyCode::ArrowDown))
+    );
+    // The escape key on its own
+    assert_eq!(
+        decoder.decode(0x1B, false),
+        Some(DecodedKey::Unicode('\x1b'))
+    );
+}
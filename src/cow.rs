@@ -0,0 +1,188 @@
+// Copy-On-Write
+// Two pages can share one frame as long as neither writes to it. Sharing a
+// page maps both to the same frame read-only, with the COW bit (one of the
+// bits the CPU leaves to the OS) set in their entries, and counts the extra
+// reference in the frame allocator. The first write to either page faults
+// and `handle_page_fault` gives the writer a copy of its own, or, if it
+// turns out to be the last one using the frame, simply makes it writable
+// again.
+//   see: https://en.wikipedia.org/wiki/Copy-on-write
+// CR0.WP has to be set for the kernel's own writes to read-only pages to
+// fault, `memory::init` takes care of that.
+use crate::frame_allocator::{self, GlobalFrameAllocator};
+use crate::memory;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{MapToError, MappedFrame, Translate, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Marks a read-only page that should get a copy of its frame when written.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug)]
+pub enum CowError {
+    /// The page to share isn't mapped, or is part of a huge page.
+    NotMapped,
+    Map(MapToError<Size4KiB>),
+}
+
+/// Map `dst` to the frame `src` is mapped to. If `src` is writable both
+/// become copy-on-write.
+///
+/// This function is unsafe because `src` stops being writable until it is
+/// written to and faults, so it must not be touched with page faults off
+/// limits, eg. while the mapper is locked.
+/// # Safety
+///    There is none. Must follow the rules.
+pub unsafe fn share_page(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    src: Page,
+    dst: Page,
+) -> Result<(), CowError> {
+    let (frame, flags) = mapped_frame(mapper, src).ok_or(CowError::NotMapped)?;
+    let flags = if flags.contains(PageTableFlags::WRITABLE) {
+        let flags = (flags - PageTableFlags::WRITABLE) | COW;
+        mapper
+            .update_flags(src, flags)
+            .map_err(|_| CowError::NotMapped)?
+            .flush();
+        flags
+    } else {
+        flags
+    };
+    mapper
+        .map_to(dst, frame, flags, &mut GlobalFrameAllocator)
+        .map_err(CowError::Map)?
+        .flush();
+    frame_allocator::share_frame(frame);
+    Ok(())
+}
+
+/// `share_page` for `count` pages at once in the kernel's page table.
+///
+/// This function is unsafe for the same reasons as `share_page`.
+/// # Safety
+///    There is none. Must follow the rules.
+pub unsafe fn share_pages(src: Page, dst: Page, count: u64) -> Result<(), CowError> {
+    memory::with_mapper(|mapper| {
+        for i in 0..count {
+            share_page(mapper, src + i, dst + i)?;
+        }
+        Ok(())
+    })
+}
+
+/// Try to resolve a page fault at `addr` as a write to a copy-on-write page.
+/// Returns false if it wasn't one, or the page tables were being changed.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_to_present =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(write_to_present) {
+        return false;
+    }
+    let page = Page::containing_address(addr);
+    memory::try_with_mapper(|mapper| unsafe { copy_on_write(mapper, page) }) == Some(true)
+}
+
+/// Give `page` a writable frame of its own if it is copy-on-write.
+///
+/// This function is unsafe because the frame `page` is mapped to must be
+/// counted in the frame allocator.
+/// # Safety
+///    There is none. Must follow the rules.
+pub unsafe fn copy_on_write(mapper: &mut (impl Mapper<Size4KiB> + Translate), page: Page) -> bool {
+    let Some((frame, flags)) = mapped_frame(mapper, page) else {
+        return false;
+    };
+    if !flags.contains(COW) {
+        return false;
+    }
+    let flags = (flags - COW) | PageTableFlags::WRITABLE;
+    // The last one left can keep the frame
+    if frame_allocator::references(frame) <= 1 {
+        return match mapper.update_flags(page, flags) {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        };
+    }
+    let Some(copy) = GlobalFrameAllocator.allocate_frame() else {
+        return false;
+    };
+    let offset = memory::physical_memory_offset();
+    core::ptr::copy_nonoverlapping(
+        (offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+        (offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+        Page::<Size4KiB>::SIZE as usize,
+    );
+    // Entries are swapped in place, unmapping can't free any tables
+    let Ok((_, flush)) = mapper.unmap(page) else {
+        GlobalFrameAllocator.deallocate_frame(copy);
+        return false;
+    };
+    flush.ignore();
+    match mapper.map_to(page, copy, flags, &mut GlobalFrameAllocator) {
+        Ok(flush) => flush.flush(),
+        Err(_) => panic!("lost the mapping of {:?} breaking copy-on-write", page),
+    }
+    // Drops our reference to the shared frame
+    GlobalFrameAllocator.deallocate_frame(frame);
+    true
+}
+
+/// The 4 KiB frame `page` is mapped to and the flags it is mapped with.
+fn mapped_frame(mapper: &impl Translate, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+    match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => Some((frame, flags)),
+        _ => None,
+    }
+}
+
+#[test_case]
+fn test_copy_on_write() {
+    use crate::vma::{self, RegionKind};
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let src = vma::map_region(4096, flags, RegionKind::Other, "cow source").unwrap();
+    let dst = vma::map_lazy_region(4096, flags, RegionKind::Other, "cow copy").unwrap();
+    let src_ptr = src.start.as_mut_ptr::<u64>();
+    let dst_ptr = dst.start.as_mut_ptr::<u64>();
+    unsafe {
+        src_ptr.write_volatile(1);
+        share_pages(
+            Page::containing_address(src.start),
+            Page::containing_address(dst.start),
+            1,
+        )
+        .unwrap();
+        assert_eq!(dst_ptr.read_volatile(), 1);
+    }
+    let frame =
+        memory::with_mapper(|mapper| mapped_frame(mapper, Page::containing_address(src.start)))
+            .unwrap()
+            .0;
+    assert_eq!(frame_allocator::references(frame), 2);
+    // The writer gets a copy, the other side keeps the original
+    unsafe {
+        dst_ptr.write_volatile(2);
+        assert_eq!(src_ptr.read_volatile(), 1);
+        assert_eq!(dst_ptr.read_volatile(), 2);
+    }
+    assert_eq!(frame_allocator::references(frame), 1);
+    // And is left alone to write to it
+    unsafe { src_ptr.write_volatile(3) };
+    let (_, src_flags) =
+        memory::with_mapper(|mapper| mapped_frame(mapper, Page::containing_address(src.start)))
+            .unwrap();
+    assert!(src_flags.contains(PageTableFlags::WRITABLE) && !src_flags.contains(COW));
+    vma::unmap_region(src.start).unwrap();
+    vma::unmap_region(dst.start).unwrap();
+}
@@ -0,0 +1,342 @@
+// ELF Loader
+// Loads statically linked x86_64 ELF executables into a fresh address space
+// to run in ring 3. Every PT_LOAD program header is a range of the file to
+// copy to a virtual address, followed by zeroes up to its size in memory,
+// mapped with the permissions in its flags. Nothing is relocated and there
+// is no dynamic linker, so the addresses have to be in the user range.
+//   see: https://wiki.osdev.org/ELF
+//   see: https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html
+// Programs start with the stack the System V ABI describes, from the stack
+// pointer up: argc, the argv pointers, a NULL, the envp pointers, a NULL,
+// the auxiliary vector of (type, value) pairs ending in AT_NULL, and above
+// it all the strings they point to.
+//   see: https://articles.manugarg.com/aboutelfauxiliaryvectors
+use crate::address_space::{AddressSpaceError, USER_END, USER_START};
+use crate::user::{Program, UserError, STACK_SIZE, STACK_TOP};
+use alloc::vec::Vec;
+use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+use x86_64::structures::paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+// Auxiliary vector types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+#[derive(Debug)]
+pub enum ElfError {
+    /// Not an ELF file, or cut short.
+    Truncated,
+    BadMagic,
+    /// Not a 64 bit little endian x86_64 executable.
+    Unsupported,
+    /// A program header points outside the file or the user range.
+    BadSegment(ProgramHeader),
+    /// The entry point isn't in an executable segment.
+    BadEntry(u64),
+    /// The arguments don't fit on the stack.
+    TooBig,
+    User(UserError),
+}
+
+impl From<UserError> for ElfError {
+    fn from(err: UserError) -> Self {
+        ElfError::User(err)
+    }
+}
+
+impl From<AddressSpaceError> for ElfError {
+    fn from(err: AddressSpaceError) -> Self {
+        ElfError::User(UserError::AddressSpace(err))
+    }
+}
+
+/// The parts of the ELF header the loader needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub entry: u64,
+    pub phoff: u64,
+    pub phnum: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+impl ProgramHeader {
+    /// How the segment's pages are mapped.
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// Check the ELF header of `image` is for something we can run.
+pub fn parse_header(image: &[u8]) -> Result<Header, ElfError> {
+    if image.len() < HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if image[0..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if image[4] != ELFCLASS64
+        || image[5] != ELFDATA2LSB
+        || image[6] != EV_CURRENT
+        || read_u16(image, 16) != ET_EXEC
+        || read_u16(image, 18) != EM_X86_64
+        || read_u16(image, 54) as usize != PROGRAM_HEADER_SIZE
+    {
+        return Err(ElfError::Unsupported);
+    }
+    let header = Header {
+        entry: read_u64(image, 24),
+        phoff: read_u64(image, 32),
+        phnum: read_u16(image, 56),
+    };
+    let table_size = header.phnum as u64 * PROGRAM_HEADER_SIZE as u64;
+    match header.phoff.checked_add(table_size) {
+        Some(end) if end <= image.len() as u64 => Ok(header),
+        _ => Err(ElfError::Truncated),
+    }
+}
+
+/// The program headers of `image`, whose header has been checked.
+pub fn program_headers<'a>(
+    image: &'a [u8],
+    header: &Header,
+) -> impl Iterator<Item = ProgramHeader> + 'a {
+    let table = &image[header.phoff as usize..];
+    table
+        .chunks_exact(PROGRAM_HEADER_SIZE)
+        .take(header.phnum as usize)
+        .map(|entry| ProgramHeader {
+            kind: read_u32(entry, 0),
+            flags: read_u32(entry, 4),
+            offset: read_u64(entry, 8),
+            vaddr: read_u64(entry, 16),
+            filesz: read_u64(entry, 32),
+            memsz: read_u64(entry, 40),
+        })
+}
+
+/// Load the executable in `image` into a new program, with `argv` and `envp`
+/// on its stack, ready to `run`.
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, ElfError> {
+    let header = parse_header(image)?;
+    let mut program = Program::new()?;
+    // Where the program headers end up in memory, if they are loaded at all
+    let mut phdr = 0;
+    for segment in program_headers(image, &header).filter(|ph| ph.kind == PT_LOAD) {
+        check_segment(image, &segment)?;
+        map_segment(&mut program, &segment)?;
+        let data = &image[segment.offset as usize..(segment.offset + segment.filesz) as usize];
+        program
+            .address_space()
+            .write(VirtAddr::new(segment.vaddr), data)?;
+        if (segment.offset..segment.offset + segment.filesz).contains(&header.phoff) {
+            phdr = segment.vaddr + header.phoff - segment.offset;
+        }
+    }
+    check_entry(image, &header)?;
+    program.set_entry(VirtAddr::new(header.entry));
+
+    let auxv = [
+        (AT_PHDR, phdr),
+        (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM, header.phnum as u64),
+        (AT_PAGESZ, Size4KiB::SIZE),
+        (AT_ENTRY, header.entry),
+    ];
+    let stack_pointer = build_stack(&mut program, argv, envp, &auxv)?;
+    program.set_stack_top(stack_pointer);
+    Ok(program)
+}
+
+fn check_segment(image: &[u8], segment: &ProgramHeader) -> Result<(), ElfError> {
+    let bad = || ElfError::BadSegment(*segment);
+    let file_end = segment.offset.checked_add(segment.filesz).ok_or_else(bad)?;
+    let end = segment.vaddr.checked_add(segment.memsz).ok_or_else(bad)?;
+    if file_end > image.len() as u64
+        || segment.filesz > segment.memsz
+        || segment.vaddr < USER_START
+        || end > USER_END
+        // Keep out of the stack
+        || end > STACK_TOP - STACK_SIZE
+    {
+        return Err(bad());
+    }
+    Ok(())
+}
+
+/// The entry point has to be somewhere loaded and executable, which also
+/// keeps it in the user range. Only call once the segments are checked.
+fn check_entry(image: &[u8], header: &Header) -> Result<(), ElfError> {
+    let entry = header.entry;
+    let executable = program_headers(image, header)
+        .filter(|ph| ph.kind == PT_LOAD && ph.flags & PF_X != 0)
+        .any(|ph| (ph.vaddr..ph.vaddr + ph.memsz).contains(&entry));
+    if !executable || !(USER_START..USER_END).contains(&entry) {
+        return Err(ElfError::BadEntry(entry));
+    }
+    Ok(())
+}
+
+/// Map zeroed pages for all of `segment`. Pages shared with a segment
+/// loaded earlier keep their frame and get the permissions of both.
+fn map_segment(program: &mut Program, segment: &ProgramHeader) -> Result<(), ElfError> {
+    if segment.memsz == 0 {
+        return Ok(());
+    }
+    let flags = segment.page_flags();
+    let start = VirtAddr::new(segment.vaddr);
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + (segment.memsz - 1)),
+    );
+    for page in pages {
+        let space = program.address_space();
+        let mapped = space.with_mapper(|mapper| {
+            let TranslateResult::Mapped { flags: old, .. } = mapper.translate(page.start_address())
+            else {
+                return false;
+            };
+            let mut both = old | flags;
+            if !(old & flags).contains(PageTableFlags::NO_EXECUTE) {
+                both.remove(PageTableFlags::NO_EXECUTE);
+            }
+            // Not active, nothing to flush
+            unsafe { mapper.update_flags(page, both) }
+                .map(|flush| flush.ignore())
+                .is_ok()
+        });
+        if !mapped {
+            space.map_zeroed(page.start_address(), Size4KiB::SIZE, flags)?;
+        }
+    }
+    Ok(())
+}
+
+/// Lay out argc, argv, envp and the auxiliary vector at the top of the
+/// program's stack and return the stack pointer to start with.
+fn build_stack(
+    program: &mut Program,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, ElfError> {
+    // The strings go at the top, NUL terminated, followed by the random
+    // bytes AT_RANDOM points at
+    let mut strings = Vec::new();
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum::<usize>() + 16;
+    let strings_start = (STACK_TOP - strings_size as u64) & !15;
+    let pointers = |list: &[&str], strings: &mut Vec<u8>| {
+        let mut addrs = Vec::with_capacity(list.len() + 1);
+        for s in list {
+            addrs.push(strings_start + strings.len() as u64);
+            strings.extend_from_slice(s.as_bytes());
+            strings.push(0);
+        }
+        addrs.push(0);
+        addrs
+    };
+    let argv_pointers = pointers(argv, &mut strings);
+    let envp_pointers = pointers(envp, &mut strings);
+    let random = strings_start + strings.len() as u64;
+    strings.extend_from_slice(&random_bytes());
+
+    let mut table = Vec::new();
+    table.push(argv.len() as u64);
+    table.extend_from_slice(&argv_pointers);
+    table.extend_from_slice(&envp_pointers);
+    for &(kind, value) in auxv.iter().chain(&[(AT_RANDOM, random), (AT_NULL, 0)]) {
+        table.push(kind);
+        table.push(value);
+    }
+    // The stack pointer has to be 16 byte aligned at the entry point
+    let table_size = (table.len() * 8) as u64;
+    let stack_pointer = (strings_start - table_size) & !15;
+    if STACK_TOP - stack_pointer > STACK_SIZE / 2 {
+        return Err(ElfError::TooBig);
+    }
+    let table: Vec<u8> = table.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let space = program.address_space();
+    space.write(VirtAddr::new(stack_pointer), &table)?;
+    space.write(VirtAddr::new(strings_start), &strings)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+/// Not cryptographically anything, programs just expect something there.
+fn random_bytes() -> [u8; 16] {
+    let seed = unsafe { core::arch::x86_64::_rdtsc() };
+    let mixed = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&seed.to_le_bytes());
+    bytes[8..].copy_from_slice(&mixed.to_le_bytes());
+    bytes
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+#[test_case]
+fn test_parse_header() {
+    let mut image = [0u8; HEADER_SIZE];
+    assert!(matches!(
+        parse_header(&image[..10]),
+        Err(ElfError::Truncated)
+    ));
+    assert!(matches!(parse_header(&image), Err(ElfError::BadMagic)));
+    image[0..4].copy_from_slice(&ELF_MAGIC);
+    assert!(matches!(parse_header(&image), Err(ElfError::Unsupported)));
+    image[4] = ELFCLASS64;
+    image[5] = ELFDATA2LSB;
+    image[6] = EV_CURRENT;
+    image[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    image[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    image[24..32].copy_from_slice(&USER_START.to_le_bytes());
+    image[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    let header = parse_header(&image).unwrap();
+    assert_eq!(header.entry, USER_START);
+    assert_eq!(program_headers(&image, &header).count(), 0);
+    // A program header table past the end of the file
+    image[32..40].copy_from_slice(&32u64.to_le_bytes());
+    image[56..58].copy_from_slice(&1u16.to_le_bytes());
+    assert!(matches!(parse_header(&image), Err(ElfError::Truncated)));
+}
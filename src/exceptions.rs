@@ -0,0 +1,419 @@
+// CPU Exceptions
+// The CPU raises an exception when an instruction can't be completed, eg. a
+// divide by zero or a bad memory access. Vectors 0 through 31 of the IDT are
+// reserved for them.
+//   see: https://wiki.osdev.org/Exceptions
+// The "x86-interrupt" calling convention doesn't let us see the general
+// purpose registers of the code that faulted, so every exception enters
+// through a small assembly stub instead. The stub pushes a fake error code if
+// the CPU didn't push one, the vector number, and then every general purpose
+// register. The result is an `ExceptionFrame` on the stack that we hand to
+// `exception_dispatch`. When it returns the registers are restored, including
+// any changes the handler made, and `iretq` resumes the faulting code.
+// Page faults in lazily backed regions are resolved by mapping the page, and
+// writes to copy-on-write pages by copying it.
+// Any exception we can't recover from prints a report with the decoded error
+// code and all the registers to both VGA and serial and then panics.
+
+use crate::{gdt, println, serial_println};
+use core::arch::global_asm;
+use core::fmt;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode, SelectorErrorCode};
+use x86_64::VirtAddr;
+
+/// Exception vectors we do something other than report and panic for.
+const DEBUG: u64 = 1;
+const BREAKPOINT: u64 = 3;
+const DOUBLE_FAULT: u64 = 8;
+const PAGE_FAULT: u64 = 14;
+
+/// The general purpose registers of the interrupted code, in the reverse
+/// order the entry stub pushes them.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// Everything on the stack when an entry stub calls `exception_dispatch`.
+/// Changes made to it are restored when the handler returns.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub registers: Registers,
+    pub vector: u64,
+    /// Zero for exceptions that don't push an error code.
+    pub error_code: u64,
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Human readable name and mnemonic of an exception vector.
+pub fn exception_name(vector: u64) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("DIVIDE ERROR", "#DE"),
+        1 => ("DEBUG", "#DB"),
+        2 => ("NON MASKABLE INTERRUPT", "NMI"),
+        3 => ("BREAKPOINT", "#BP"),
+        4 => ("OVERFLOW", "#OF"),
+        5 => ("BOUND RANGE EXCEEDED", "#BR"),
+        6 => ("INVALID OPCODE", "#UD"),
+        7 => ("DEVICE NOT AVAILABLE", "#NM"),
+        8 => ("DOUBLE FAULT", "#DF"),
+        10 => ("INVALID TSS", "#TS"),
+        11 => ("SEGMENT NOT PRESENT", "#NP"),
+        12 => ("STACK SEGMENT FAULT", "#SS"),
+        13 => ("GENERAL PROTECTION FAULT", "#GP"),
+        14 => ("PAGE FAULT", "#PF"),
+        16 => ("X87 FLOATING POINT", "#MF"),
+        17 => ("ALIGNMENT CHECK", "#AC"),
+        18 => ("MACHINE CHECK", "#MC"),
+        19 => ("SIMD FLOATING POINT", "#XM"),
+        20 => ("VIRTUALIZATION", "#VE"),
+        21 => ("CONTROL PROTECTION", "#CP"),
+        29 => ("VMM COMMUNICATION", "#VC"),
+        30 => ("SECURITY", "#SX"),
+        _ => ("RESERVED", "??"),
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, mnemonic) = exception_name(self.vector);
+        writeln!(
+            f,
+            "EXCEPTION: {} ({}, vector {})",
+            name, mnemonic, self.vector
+        )?;
+
+        match self.vector {
+            // Selector error codes point at the segment descriptor involved
+            10..=13 if self.error_code == 0 => writeln!(f, "Error Code: 0 (not segment related)")?,
+            10..=13 => writeln!(
+                f,
+                "Error Code: {:#x} {:?}",
+                self.error_code,
+                SelectorErrorCode::new_truncate(self.error_code)
+            )?,
+            PAGE_FAULT => {
+                writeln!(f, "Accessed Address: {:?}", Cr2::read())?;
+                writeln!(
+                    f,
+                    "Error Code: {:#x} {:?}",
+                    self.error_code,
+                    PageFaultErrorCode::from_bits_truncate(self.error_code)
+                )?;
+            }
+            DOUBLE_FAULT | 17 | 21 | 29 | 30 => writeln!(f, "Error Code: {:#x}", self.error_code)?,
+            _ => {}
+        }
+
+        let r = &self.registers;
+        writeln!(
+            f,
+            "RIP={:#018x} CS={:#06x} RFLAGS={:#018x}",
+            self.rip, self.cs, self.rflags
+        )?;
+        writeln!(f, "RSP={:#018x} SS={:#06x}", self.rsp, self.ss)?;
+        writeln!(
+            f,
+            "CR0={:#018x} CR2={:#018x}",
+            Cr0::read_raw(),
+            Cr2::read_raw()
+        )?;
+        writeln!(
+            f,
+            "CR3={:#018x} CR4={:#018x}",
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw()
+        )?;
+        writeln!(
+            f,
+            "RAX={:#018x} RBX={:#018x} RCX={:#018x}",
+            r.rax, r.rbx, r.rcx
+        )?;
+        writeln!(
+            f,
+            "RDX={:#018x} RSI={:#018x} RDI={:#018x}",
+            r.rdx, r.rsi, r.rdi
+        )?;
+        writeln!(
+            f,
+            "RBP={:#018x} R8 ={:#018x} R9 ={:#018x}",
+            r.rbp, r.r8, r.r9
+        )?;
+        writeln!(
+            f,
+            "R10={:#018x} R11={:#018x} R12={:#018x}",
+            r.r10, r.r11, r.r12
+        )?;
+        write!(
+            f,
+            "R13={:#018x} R14={:#018x} R15={:#018x}",
+            r.r13, r.r14, r.r15
+        )
+    }
+}
+
+/// The last frame `exception_dispatch` was handed, for the tests to check.
+#[cfg(test)]
+static LAST_FRAME: spin::Mutex<Option<ExceptionFrame>> = spin::Mutex::new(None);
+
+/// Called by every entry stub with the frame it built on the stack.
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    #[cfg(test)]
+    if let Some(mut last) = LAST_FRAME.try_lock() {
+        *last = Some(*frame);
+    }
+    match frame.vector {
+        BREAKPOINT | DEBUG if crate::gdb::is_enabled() => crate::gdb::handle_exception(frame),
+        BREAKPOINT => println!("EXCEPTION: BREAKPOINT\n{:#?}", frame),
+        DEBUG => println!("EXCEPTION: DEBUG\n{:#?}", frame),
+        // Returning runs the faulting instruction again, now with the page
+        PAGE_FAULT if resolve_page_fault(frame) => {}
+        // Only the program has to go
+        _ if frame.cs & 3 == 3 => crate::user::kill(frame),
+        _ => fatal(frame),
+    }
+}
+
+/// Map the page if the fault was the first touch of a lazily backed region,
+/// or copy it if it was a write to a copy-on-write page.
+fn resolve_page_fault(frame: &ExceptionFrame) -> bool {
+    let Ok(addr) = VirtAddr::try_new(Cr2::read_raw()) else {
+        return false;
+    };
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    crate::vma::handle_page_fault(addr, error_code)
+        || crate::cow::handle_page_fault(addr, error_code)
+}
+
+/// Report an exception we can't recover from.
+fn fatal(frame: &ExceptionFrame) -> ! {
+    println!("{}", frame);
+    serial_println!("{}", frame);
+    crate::backtrace::backtrace_from(frame.rip, frame.registers.rbp);
+    // Running into a guard page faults, and when there is no stack left to
+    // handle that on it turns into a double fault
+    if let DOUBLE_FAULT | PAGE_FAULT = frame.vector {
+        if let Some(name) = crate::stack::overflowed_stack() {
+            panic!("stack overflow in {}", name);
+        }
+    }
+    panic!("EXCEPTION: {}", exception_name(frame.vector).0);
+}
+
+// Saves the registers, calls `exception_dispatch` and restores them again.
+// The CPU aligns the stack to 16 bytes before pushing its 5 words, and with
+// the error code, vector and 15 registers we are still aligned at the `call`.
+global_asm!(
+    ".global exception_common",
+    "exception_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call exception_dispatch",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // Drop the vector and error code
+    "add rsp, 16",
+    "iretq",
+);
+
+/// Define an entry stub for an exception the CPU doesn't push an error code
+/// for. A zero is pushed in its place so every frame looks the same.
+macro_rules! exception_stub {
+    ($name:ident, $vector:literal) => {
+        global_asm!(
+            concat!(".global ", stringify!($name)),
+            concat!(stringify!($name), ":"),
+            "push 0",
+            concat!("push ", $vector),
+            "jmp exception_common",
+        );
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+
+/// Define an entry stub for an exception the CPU pushes an error code for.
+macro_rules! exception_stub_with_error_code {
+    ($name:ident, $vector:literal) => {
+        global_asm!(
+            concat!(".global ", stringify!($name)),
+            concat!(stringify!($name), ":"),
+            concat!("push ", $vector),
+            "jmp exception_common",
+        );
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+
+exception_stub!(divide_error_stub, 0);
+exception_stub!(debug_stub, 1);
+exception_stub!(non_maskable_interrupt_stub, 2);
+exception_stub!(breakpoint_stub, 3);
+exception_stub!(overflow_stub, 4);
+exception_stub!(bound_range_exceeded_stub, 5);
+exception_stub!(invalid_opcode_stub, 6);
+exception_stub!(device_not_available_stub, 7);
+exception_stub_with_error_code!(double_fault_stub, 8);
+exception_stub_with_error_code!(invalid_tss_stub, 10);
+exception_stub_with_error_code!(segment_not_present_stub, 11);
+exception_stub_with_error_code!(stack_segment_fault_stub, 12);
+exception_stub_with_error_code!(general_protection_fault_stub, 13);
+exception_stub_with_error_code!(page_fault_stub, 14);
+exception_stub!(x87_floating_point_stub, 16);
+exception_stub_with_error_code!(alignment_check_stub, 17);
+exception_stub!(machine_check_stub, 18);
+exception_stub!(simd_floating_point_stub, 19);
+exception_stub!(virtualization_stub, 20);
+exception_stub_with_error_code!(control_protection_stub, 21);
+exception_stub_with_error_code!(vmm_communication_exception_stub, 29);
+exception_stub_with_error_code!(security_exception_stub, 30);
+
+/// The IDT entry types only accept Rust functions of the matching signature
+/// but all they store is the address, so hand them the stub's address.
+unsafe fn stub<F>(stub: unsafe extern "C" fn()) -> F {
+    core::mem::transmute_copy(&stub)
+}
+
+/// Point every exception vector of `idt` at its entry stub.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_fn(stub(divide_error_stub));
+        idt.debug.set_handler_fn(stub(debug_stub));
+        idt.non_maskable_interrupt
+            .set_handler_fn(stub(non_maskable_interrupt_stub))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_fn(stub(breakpoint_stub));
+        idt.overflow.set_handler_fn(stub(overflow_stub));
+        idt.bound_range_exceeded
+            .set_handler_fn(stub(bound_range_exceeded_stub));
+        idt.invalid_opcode.set_handler_fn(stub(invalid_opcode_stub));
+        idt.device_not_available
+            .set_handler_fn(stub(device_not_available_stub));
+        idt.double_fault
+            .set_handler_fn(stub(double_fault_stub))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_fn(stub(invalid_tss_stub));
+        idt.segment_not_present
+            .set_handler_fn(stub(segment_not_present_stub));
+        idt.stack_segment_fault
+            .set_handler_fn(stub(stack_segment_fault_stub));
+        idt.general_protection_fault
+            .set_handler_fn(stub(general_protection_fault_stub));
+        idt.page_fault.set_handler_fn(stub(page_fault_stub));
+        idt.x87_floating_point
+            .set_handler_fn(stub(x87_floating_point_stub));
+        idt.alignment_check
+            .set_handler_fn(stub(alignment_check_stub));
+        idt.machine_check
+            .set_handler_fn(stub(machine_check_stub))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point
+            .set_handler_fn(stub(simd_floating_point_stub));
+        idt.virtualization.set_handler_fn(stub(virtualization_stub));
+        idt.cp_protection_exception
+            .set_handler_fn(stub(control_protection_stub));
+        idt.vmm_communication_exception
+            .set_handler_fn(stub(vmm_communication_exception_stub));
+        idt.security_exception
+            .set_handler_fn(stub(security_exception_stub));
+    }
+}
+
+#[test_case]
+fn test_exception_names() {
+    assert_eq!(exception_name(13), ("GENERAL PROTECTION FAULT", "#GP"));
+    assert_eq!(exception_name(31), ("RESERVED", "??"));
+}
+
+#[test_case]
+fn test_exception_frame() {
+    use crate::vma::{self, RegionKind};
+    use core::arch::asm;
+    use x86_64::structures::paging::PageTableFlags;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let region = vma::map_lazy_region(4096, flags, RegionKind::Other, "test fault").unwrap();
+    let addr = region.start.as_u64();
+    let value = 0x1234_5678_9abc_def0u64;
+    // The first write to the region faults, gets the page mapped and is run
+    // again, with the registers it uses the same as they were
+    let rip = x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let rip: u64;
+        asm!(
+            "lea {rip}, [rip + 2f]",
+            "2:",
+            "mov qword ptr [r12], r13",
+            rip = out(reg) rip,
+            in("r12") addr,
+            in("r13") value,
+            options(nostack),
+        );
+        rip
+    });
+    let frame = LAST_FRAME.lock().take().unwrap();
+    assert_eq!(frame.vector, PAGE_FAULT);
+    assert_eq!(frame.error_code, PageFaultErrorCode::CAUSED_BY_WRITE.bits());
+    assert_eq!(frame.rip, rip);
+    assert_eq!(frame.cs, u64::from(gdt::selectors().code_selector.0));
+    assert_eq!(frame.registers.r12, addr);
+    assert_eq!(frame.registers.r13, value);
+    assert_eq!(
+        unsafe { region.start.as_ptr::<u64>().read_volatile() },
+        value
+    );
+    vma::unmap_region(region.start).unwrap();
+}
@@ -0,0 +1,387 @@
+// Physical Frame Allocator
+// Keeps track of which 4 KiB frames of physical memory are in use with a
+// bitmap, one bit per frame, set when the frame is used.
+//   see: https://wiki.osdev.org/Page_Frame_Allocation
+// The bitmap covers everything up to the end of the last usable region in the
+// bootloader's memory map. It has to live somewhere before there is a heap,
+// so it takes the start of the first usable region that is big enough and is
+// accessed through the bootloader's mapping of physical memory. Everything
+// the memory map doesn't mark usable, and the bitmap itself, starts out used
+// and is never handed out. A second bitmap remembers which frames those are,
+// so freeing one of them is caught instead of adding it to the free frames.
+// 2 MiB and 1 GiB frames are runs of 512 or 512 * 512 free frames starting on
+// a matching boundary. Those runs are a whole number of bitmap words so they
+// are found by looking for all-zero words.
+// Frames can also be shared, eg. by copy-on-write mappings. Next to the
+// bitmap is a count of the extra references to every frame, zero for frames
+// with a single owner, and deallocating a shared frame only drops one of
+// them. The frame is freed along with its last reference.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Frames tracked by each word of the bitmap.
+const FRAMES_PER_WORD: u64 = u64::BITS as u64;
+
+/// The kernel's frame allocator, ready once `init` has been called.
+/// Prefer `GlobalFrameAllocator` which takes the lock with interrupts off.
+pub static FRAME_ALLOCATOR: Mutex<BitmapFrameAllocator> = Mutex::new(BitmapFrameAllocator::empty());
+
+/// Set up the global frame allocator from the bootloader's memory map.
+///
+/// This function is unsafe because the caller must guarantee that the passed
+/// memory map is valid, that all frames marked as `USABLE` in it are really
+/// unused, and that the complete physical memory is mapped to virtual memory
+/// at the passed `physical_memory_offset`.
+/// # Safety
+///    There is none. Must follow the rules.
+pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) {
+    let allocator = BitmapFrameAllocator::init(memory_map, physical_memory_offset);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *FRAME_ALLOCATOR.lock() = allocator;
+    });
+}
+
+/// Add a reference to a used frame, it won't be freed until every reference
+/// has been deallocated.
+pub fn share_frame(frame: PhysFrame) {
+    x86_64::instructions::interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().share(frame))
+}
+
+/// How many references there are to `frame`, zero if it is free.
+pub fn references(frame: PhysFrame) -> u32 {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().references(frame)
+    })
+}
+
+/// Statistics of the global frame allocator.
+pub fn stats() -> FrameStats {
+    x86_64::instructions::interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().stats())
+}
+
+/// How much physical memory there is, counted in 4 KiB frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames the memory map marked usable.
+    pub total_frames: u64,
+    pub used_frames: u64,
+    pub free_frames: u64,
+}
+
+/// Hands out frames from the global `FRAME_ALLOCATOR`, for everything that
+/// takes a `FrameAllocator` or `FrameDeallocator`.
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl<S: PageSize> FrameAllocator<S> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR.lock().allocate_frame()
+        })
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR.lock().deallocate_frame(frame)
+        })
+    }
+}
+
+/// A frame allocator backed by a bitmap of every frame.
+pub struct BitmapFrameAllocator {
+    /// Bit `n % 64` of word `n / 64` is set when frame `n` is used.
+    bitmap: &'static mut [u64],
+    /// Set for the frames that can be handed out, the same way.
+    usable: &'static mut [u64],
+    /// References to each frame beyond the first.
+    shares: &'static mut [u16],
+    total_frames: u64,
+    free_frames: u64,
+    /// Where to start looking for a free 4 KiB frame.
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// An allocator with no memory to give out.
+    pub const fn empty() -> Self {
+        BitmapFrameAllocator {
+            bitmap: &mut [],
+            usable: &mut [],
+            shares: &mut [],
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        }
+    }
+
+    /// Create a frame allocator from the passed memory map.
+    ///
+    /// This function is unsafe for the same reasons as `init`.
+    /// # Safety
+    ///    There is none. Must follow the rules.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+        let end_frame = usable()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0);
+        let words = end_frame.div_ceil(FRAMES_PER_WORD) as usize;
+        let frames = words * FRAMES_PER_WORD as usize;
+        // Both bitmaps are a multiple of 8 bytes, the counts stay aligned
+        let bitmap_bytes = (words * 8) as u64;
+        let shares_bytes = (frames * 2) as u64;
+        let home_bytes = 2 * bitmap_bytes + shares_bytes;
+        let Some(home) = usable().find(|r| r.range.end_addr() - r.range.start_addr() >= home_bytes)
+        else {
+            return Self::empty();
+        };
+
+        let bitmap_start = physical_memory_offset + home.range.start_addr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_start.as_mut_ptr::<u64>(), words);
+        bitmap.fill(!0);
+        let usable_start = bitmap_start + bitmap_bytes;
+        let usable_bits = core::slice::from_raw_parts_mut(usable_start.as_mut_ptr::<u64>(), words);
+        usable_bits.fill(0);
+        let shares_start = usable_start + bitmap_bytes;
+        let shares = core::slice::from_raw_parts_mut(shares_start.as_mut_ptr::<u16>(), frames);
+        shares.fill(0);
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            usable: usable_bits,
+            shares,
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+        for region in usable() {
+            let (start, end) = (
+                region.range.start_frame_number,
+                region.range.end_frame_number,
+            );
+            allocator.set_range(start, end - start, false);
+            allocator.set_usable(start, end - start, true);
+            allocator.total_frames += end - start;
+        }
+        let bitmap_frames = home_bytes.div_ceil(Size4KiB::SIZE);
+        allocator.set_range(home.range.start_frame_number, bitmap_frames, true);
+        allocator.set_usable(home.range.start_frame_number, bitmap_frames, false);
+        allocator
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total_frames,
+            used_frames: self.total_frames - self.free_frames,
+            free_frames: self.free_frames,
+        }
+    }
+
+    /// Add a reference to a used frame.
+    pub fn share(&mut self, frame: PhysFrame) {
+        let number = frame.start_address().as_u64() / Size4KiB::SIZE;
+        assert!(
+            self.is_usable(number),
+            "sharing frame {:#x}, which isn't usable memory",
+            frame.start_address()
+        );
+        assert!(
+            self.is_used(number),
+            "sharing free frame {:#x}",
+            frame.start_address()
+        );
+        let shares = &mut self.shares[number as usize];
+        *shares = shares
+            .checked_add(1)
+            .expect("too many references to a frame");
+    }
+
+    /// How many references there are to `frame`, zero if it is free.
+    pub fn references(&self, frame: PhysFrame) -> u32 {
+        let number = frame.start_address().as_u64() / Size4KiB::SIZE;
+        if !self.is_used(number) {
+            return 0;
+        }
+        self.shares
+            .get(number as usize)
+            .map_or(1, |&shares| shares as u32 + 1)
+    }
+
+    /// Whether frame number `frame` is in use.
+    fn is_used(&self, frame: u64) -> bool {
+        let word = (frame / FRAMES_PER_WORD) as usize;
+        self.bitmap
+            .get(word)
+            .is_none_or(|bits| bits & (1 << (frame % FRAMES_PER_WORD)) != 0)
+    }
+
+    /// Whether frame number `frame` is usable memory the allocator can hand
+    /// out and take back.
+    fn is_usable(&self, frame: u64) -> bool {
+        let word = (frame / FRAMES_PER_WORD) as usize;
+        self.usable
+            .get(word)
+            .is_some_and(|bits| bits & (1 << (frame % FRAMES_PER_WORD)) != 0)
+    }
+
+    /// Mark `count` frames starting at frame number `start` usable or not.
+    fn set_usable(&mut self, start: u64, count: u64, usable: bool) {
+        for frame in start..start + count {
+            let word = &mut self.usable[(frame / FRAMES_PER_WORD) as usize];
+            let bit = 1 << (frame % FRAMES_PER_WORD);
+            if usable {
+                *word |= bit;
+            } else {
+                *word &= !bit;
+            }
+        }
+    }
+
+    /// Mark `count` frames starting at frame number `start` used or free.
+    fn set_range(&mut self, start: u64, count: u64, used: bool) {
+        for frame in start..start + count {
+            let word = &mut self.bitmap[(frame / FRAMES_PER_WORD) as usize];
+            let bit = 1 << (frame % FRAMES_PER_WORD);
+            if used {
+                *word |= bit;
+                self.free_frames -= 1;
+            } else {
+                *word &= !bit;
+                self.free_frames += 1;
+            }
+        }
+    }
+
+    /// Find and take a single free frame.
+    fn allocate_one(&mut self) -> Option<u64> {
+        let words = self.bitmap.len();
+        let word = (0..words)
+            .map(|i| (self.next_word + i) % words)
+            .find(|&i| self.bitmap[i] != !0)?;
+        self.next_word = word;
+        let frame = word as u64 * FRAMES_PER_WORD + self.bitmap[word].trailing_ones() as u64;
+        self.set_range(frame, 1, true);
+        Some(frame)
+    }
+
+    /// Find and take `count` free frames starting on a multiple of `count`,
+    /// which must be a multiple of `FRAMES_PER_WORD`.
+    fn allocate_run(&mut self, count: u64) -> Option<u64> {
+        let run_words = (count / FRAMES_PER_WORD) as usize;
+        let start_word = self
+            .bitmap
+            .chunks_exact(run_words)
+            .position(|run| run.iter().all(|&bits| bits == 0))?
+            * run_words;
+        let frame = start_word as u64 * FRAMES_PER_WORD;
+        self.set_range(frame, count, true);
+        Some(frame)
+    }
+}
+
+unsafe impl<S: PageSize> FrameAllocator<S> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let count = S::SIZE / Size4KiB::SIZE;
+        let frame = if count == 1 {
+            self.allocate_one()?
+        } else {
+            self.allocate_run(count)?
+        };
+        let addr = PhysAddr::new(frame * Size4KiB::SIZE);
+        PhysFrame::from_start_address(addr).ok()
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let start = frame.start_address().as_u64() / Size4KiB::SIZE;
+        let count = S::SIZE / Size4KiB::SIZE;
+        for frame in start..start + count {
+            assert!(
+                self.is_usable(frame),
+                "freeing frame {:#x}, which isn't usable memory",
+                frame * Size4KiB::SIZE
+            );
+        }
+        // Only drop a reference if someone else still has the frame
+        if let Some(shares @ 1..) = self.shares.get_mut(start as usize) {
+            assert_eq!(count, 1, "huge frames can't be shared");
+            *shares -= 1;
+            return;
+        }
+        for frame in start..start + count {
+            assert!(
+                self.is_used(frame),
+                "double free of frame {:#x}",
+                frame * Size4KiB::SIZE
+            );
+        }
+        self.set_range(start, count, false);
+        self.next_word = self.next_word.min((start / FRAMES_PER_WORD) as usize);
+    }
+}
+
+#[test_case]
+fn test_allocate_and_free_frame() {
+    let mut allocator = GlobalFrameAllocator;
+    let before = stats();
+    let frame: PhysFrame = allocator.allocate_frame().unwrap();
+    assert_eq!(stats().used_frames, before.used_frames + 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(stats(), before);
+}
+
+#[test_case]
+fn test_shared_frame() {
+    let mut allocator = GlobalFrameAllocator;
+    let before = stats();
+    let frame: PhysFrame = allocator.allocate_frame().unwrap();
+    share_frame(frame);
+    assert_eq!(references(frame), 2);
+    unsafe { allocator.deallocate_frame(frame) };
+    // Still used by the other reference
+    assert_eq!(references(frame), 1);
+    assert_eq!(stats().used_frames, before.used_frames + 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(references(frame), 0);
+    assert_eq!(stats(), before);
+}
+
+#[test_case]
+fn test_allocate_huge_frame() {
+    use x86_64::structures::paging::Size2MiB;
+
+    let mut allocator = GlobalFrameAllocator;
+    let before = stats();
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+    assert_eq!(stats().used_frames, before.used_frames + 512);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(stats(), before);
+}
+
+#[test_case]
+fn test_only_usable_frames() {
+    let mut allocator = GlobalFrameAllocator;
+    let frame: PhysFrame = allocator.allocate_frame().unwrap();
+    let number = frame.start_address().as_u64() / Size4KiB::SIZE;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let frames = FRAME_ALLOCATOR.lock();
+        assert!(frames.is_usable(number));
+        // Past the end of the bitmap
+        assert!(!frames.is_usable(frames.bitmap.len() as u64 * FRAMES_PER_WORD));
+        // Where the bitmaps themselves live
+        let home = (frames.bitmap.as_ptr() as u64
+            - crate::memory::physical_memory_offset().as_u64())
+            / Size4KiB::SIZE;
+        assert!(!frames.is_usable(home));
+    });
+    unsafe { allocator.deallocate_frame(frame) };
+}
@@ -0,0 +1,580 @@
+// GDB Remote Serial Protocol stub
+// Lets `gdb` on the host debug the kernel through the second serial port. Run
+// QEMU with COM1 as usual and COM2 on a socket:
+//   cargo run --features gdb -- -serial stdio -serial tcp::1234,server,nowait
+// and attach with:
+//   gdb target/x86_64-blog_os/debug/greg_os -ex 'target remote :1234'
+// The stub takes over whenever the CPU raises a breakpoint (`int3`) or debug
+// exception, and F12 in the resume viewer breaks in on demand. While it runs
+// interrupts are off and the rest of the kernel is frozen. The interrupted
+// registers live in the `ExceptionFrame` so reads and writes go straight to
+// it and take effect when the exception handler returns.
+// Packets look like `$<data>#<two hex digit checksum>` and each one is
+// acknowledged with `+`, or `-` to ask for it again.
+//   see: https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//   see: https://wiki.osdev.org/GDB
+// Everything here runs inside an exception handler, so no heap and no locks
+// shared with the rest of the kernel.
+
+use crate::exceptions::ExceptionFrame;
+use crate::serial::{self, ComPort, SerialPort};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::structures::paging::page_table::FrameError;
+use x86_64::structures::paging::PageTable;
+use x86_64::VirtAddr;
+
+/// Largest packet we accept or send, advertised to gdb in `qSupported`.
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xCC;
+/// RFLAGS.TF raises a debug exception after every instruction.
+const TRAP_FLAG: u64 = 1 << 8;
+/// Every stop is reported as a SIGTRAP.
+const STOP_REPLY: &[u8] = b"S05";
+
+/// The registers in the order of gdb's default x86-64 `g` packet. The first
+/// 17 are 8 bytes wide and the rest 4 bytes. Anything after the segment
+/// registers we leave out and gdb shows as unavailable.
+const REGISTER_COUNT: usize = 24;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static STUB: Mutex<Option<GdbStub>> = Mutex::new(None);
+
+/// Open COM2 and start handling breakpoint and debug exceptions.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`.
+/// # Safety
+///    There is none. Must follow the rules.
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    // Our own handle rather than `serial::port` so we never wait on a lock
+    // the interrupted code holds
+    let mut port = SerialPort::new(ComPort::Com2.base());
+    port.init(serial::DEFAULT_BAUD);
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    *STUB.lock() = Some(GdbStub {
+        port,
+        breakpoints: [None; MAX_BREAKPOINTS],
+        packet: [0; PACKET_SIZE],
+        reply: Reply::new(),
+    });
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Whether `init` has been called and breakpoints go to gdb. Never in the
+/// kernel's own tests, which don't call it.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Stop and hand control to gdb, if it is enabled.
+pub fn breakpoint() {
+    if is_enabled() {
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+/// Talk to gdb until it tells us to continue or step.
+/// Called from the breakpoint and debug exception handlers.
+pub fn handle_exception(frame: &mut ExceptionFrame) {
+    let mut guard = STUB.lock();
+    let Some(stub) = guard.as_mut() else {
+        return;
+    };
+    // We only single-step when asked to, every time
+    frame.rflags &= !TRAP_FLAG;
+    stub.send_packet(STOP_REPLY);
+    loop {
+        let len = stub.receive_packet();
+        let GdbStub {
+            port,
+            breakpoints,
+            packet,
+            reply,
+        } = &mut *stub;
+        reply.clear();
+        let resume = handle_packet(&packet[..len], frame, breakpoints, reply);
+        match resume {
+            Some(resume) => {
+                // Detaching still expects its OK
+                if reply.len > 0 {
+                    send_packet(port, reply.as_bytes());
+                }
+                if let Resume::Step = resume {
+                    frame.rflags |= TRAP_FLAG;
+                }
+                return;
+            }
+            None => send_packet(port, reply.as_bytes()),
+        }
+    }
+}
+
+/// What to do after a packet has been handled.
+enum Resume {
+    Continue,
+    Step,
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    /// The byte `int3` replaced.
+    original: u8,
+}
+
+struct GdbStub {
+    port: SerialPort,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+}
+
+impl GdbStub {
+    /// Wait for a packet with a valid checksum and return its length.
+    fn receive_packet(&mut self) -> usize {
+        loop {
+            // Skip acks and interrupt requests until the start of a packet
+            while self.port.receive() != b'$' {}
+            let mut len = 0;
+            let mut checksum = 0u8;
+            loop {
+                let byte = self.port.receive();
+                if byte == b'#' {
+                    break;
+                }
+                if len < PACKET_SIZE {
+                    self.packet[len] = byte;
+                    len += 1;
+                }
+                checksum = checksum.wrapping_add(byte);
+            }
+            let high = from_hex_digit(self.port.receive());
+            let low = from_hex_digit(self.port.receive());
+            if high.zip(low).map(|(high, low)| high << 4 | low) == Some(checksum) {
+                self.port.send(b'+');
+                return len;
+            }
+            self.port.send(b'-');
+        }
+    }
+
+    fn send_packet(&mut self, data: &[u8]) {
+        send_packet(&mut self.port, data);
+    }
+}
+
+/// Send a packet and wait for gdb to acknowledge it.
+fn send_packet(port: &mut SerialPort, data: &[u8]) {
+    loop {
+        port.send(b'$');
+        let mut checksum = 0u8;
+        for &byte in data {
+            port.send(byte);
+            checksum = checksum.wrapping_add(byte);
+        }
+        port.send(b'#');
+        port.send(hex_digit(checksum >> 4));
+        port.send(hex_digit(checksum & 0xF));
+        if port.receive() != b'-' {
+            return;
+        }
+    }
+}
+
+/// A reply packet being built.
+struct Reply {
+    buffer: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    const fn new() -> Self {
+        Reply {
+            buffer: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let end = (self.len + bytes.len()).min(PACKET_SIZE);
+        self.buffer[self.len..end].copy_from_slice(&bytes[..end - self.len]);
+        self.len = end;
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        self.push(&[hex_digit(byte >> 4), hex_digit(byte & 0xF)]);
+    }
+
+    fn ok(&mut self) {
+        self.push(b"OK");
+    }
+
+    fn error(&mut self, code: u8) {
+        self.push(b"E");
+        self.push_hex(code);
+    }
+}
+
+// Error numbers are errno values, gdb just prints them
+const EFAULT: u8 = 14;
+const EINVAL: u8 = 22;
+const ENOSPC: u8 = 28;
+
+/// Handle a single packet, filling in `reply` unless gdb asked to resume.
+fn handle_packet(
+    packet: &[u8],
+    frame: &mut ExceptionFrame,
+    breakpoints: &mut [Option<Breakpoint>],
+    reply: &mut Reply,
+) -> Option<Resume> {
+    let (&command, args) = packet.split_first()?;
+    match command {
+        b'?' => reply.push(STOP_REPLY),
+        b'g' => {
+            for n in 0..REGISTER_COUNT {
+                push_register(reply, frame, n);
+            }
+        }
+        b'G' => {
+            let mut hex = args;
+            for n in 0..REGISTER_COUNT {
+                let size = register_size(n);
+                let Some(value) = hex.get(..size * 2).and_then(parse_hex_le) else {
+                    break;
+                };
+                set_register(frame, n, value);
+                hex = &hex[size * 2..];
+            }
+            reply.ok();
+        }
+        b'p' => match parse_hex(args) {
+            Some(n) if (n as usize) < REGISTER_COUNT => push_register(reply, frame, n as usize),
+            // An empty reply tells gdb to fall back to `g`
+            _ => {}
+        },
+        b'P' => {
+            let parsed = split_once(args, b'=')
+                .and_then(|(n, value)| Some((parse_hex(n)? as usize, parse_hex_le(value)?)));
+            match parsed {
+                Some((n, value)) if n < REGISTER_COUNT => {
+                    set_register(frame, n, value);
+                    reply.ok();
+                }
+                _ => reply.error(EINVAL),
+            }
+        }
+        b'm' => match parse_address_length(args) {
+            Some((addr, len)) if len <= (PACKET_SIZE / 2) as u64 => {
+                if is_mapped(addr, len) {
+                    for i in 0..len {
+                        reply.push_hex(read_byte(addr + i, breakpoints));
+                    }
+                } else {
+                    reply.error(EFAULT);
+                }
+            }
+            _ => reply.error(EINVAL),
+        },
+        b'M' => {
+            let parsed = split_once(args, b':')
+                .and_then(|(range, data)| Some((parse_address_length(range)?, data)));
+            match parsed {
+                Some(((addr, len), data)) if data.len() as u64 == len * 2 => {
+                    if is_mapped(addr, len) {
+                        for (i, pair) in data.chunks(2).enumerate() {
+                            let byte = parse_hex(pair).unwrap_or(0) as u8;
+                            write_byte(addr + i as u64, byte, breakpoints);
+                        }
+                        reply.ok();
+                    } else {
+                        reply.error(EFAULT);
+                    }
+                }
+                _ => reply.error(EINVAL),
+            }
+        }
+        b'Z' | b'z' => {
+            // Only software breakpoints, anything else gets an empty reply
+            if let Some((addr, _kind)) = args.strip_prefix(b"0,").and_then(parse_address_length) {
+                let result = if command == b'Z' {
+                    insert_breakpoint(addr, breakpoints)
+                } else {
+                    remove_breakpoint(addr, breakpoints)
+                };
+                match result {
+                    Ok(()) => reply.ok(),
+                    Err(code) => reply.error(code),
+                }
+            }
+        }
+        b'c' | b's' => {
+            if let Some(addr) = parse_hex(args) {
+                frame.rip = addr;
+            }
+            return Some(if command == b'c' {
+                Resume::Continue
+            } else {
+                Resume::Step
+            });
+        }
+        // Detaching or killing just lets the kernel carry on
+        b'D' | b'k' => {
+            for slot in breakpoints.iter_mut() {
+                if let Some(breakpoint) = slot.take() {
+                    unsafe { write_memory(breakpoint.addr, breakpoint.original) };
+                }
+            }
+            if command == b'D' {
+                reply.ok();
+            }
+            return Some(Resume::Continue);
+        }
+        // There is only one thread
+        b'H' => reply.ok(),
+        b'q' => {
+            if args.starts_with(b"Supported") {
+                reply.push(b"PacketSize=1000");
+            } else if args == b"Attached" {
+                reply.push(b"1");
+            }
+        }
+        _ => {}
+    }
+    None
+}
+
+fn register_size(n: usize) -> usize {
+    if n <= RIP {
+        8
+    } else {
+        4
+    }
+}
+
+fn register(frame: &ExceptionFrame, n: usize) -> u64 {
+    let r = &frame.registers;
+    match n {
+        0 => r.rax,
+        1 => r.rbx,
+        2 => r.rcx,
+        3 => r.rdx,
+        4 => r.rsi,
+        5 => r.rdi,
+        6 => r.rbp,
+        7 => frame.rsp,
+        8 => r.r8,
+        9 => r.r9,
+        10 => r.r10,
+        11 => r.r11,
+        12 => r.r12,
+        13 => r.r13,
+        14 => r.r14,
+        15 => r.r15,
+        RIP => frame.rip,
+        EFLAGS => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        // ds, es, fs and gs are unused in long mode
+        _ => 0,
+    }
+}
+
+fn set_register(frame: &mut ExceptionFrame, n: usize, value: u64) {
+    let r = &mut frame.registers;
+    let register = match n {
+        0 => &mut r.rax,
+        1 => &mut r.rbx,
+        2 => &mut r.rcx,
+        3 => &mut r.rdx,
+        4 => &mut r.rsi,
+        5 => &mut r.rdi,
+        6 => &mut r.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut r.r8,
+        9 => &mut r.r9,
+        10 => &mut r.r10,
+        11 => &mut r.r11,
+        12 => &mut r.r12,
+        13 => &mut r.r13,
+        14 => &mut r.r14,
+        15 => &mut r.r15,
+        RIP => &mut frame.rip,
+        EFLAGS => &mut frame.rflags,
+        // Loading a bad segment selector on iretq would just fault again
+        _ => return,
+    };
+    *register = value;
+}
+
+/// Registers are sent as little endian hex.
+fn push_register(reply: &mut Reply, frame: &ExceptionFrame, n: usize) {
+    let bytes = register(frame, n).to_le_bytes();
+    for &byte in &bytes[..register_size(n)] {
+        reply.push_hex(byte);
+    }
+}
+
+fn insert_breakpoint(addr: u64, breakpoints: &mut [Option<Breakpoint>]) -> Result<(), u8> {
+    if breakpoints.iter().flatten().any(|b| b.addr == addr) {
+        return Ok(());
+    }
+    if !is_mapped(addr, 1) {
+        return Err(EFAULT);
+    }
+    let slot = breakpoints
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(ENOSPC)?;
+    unsafe {
+        *slot = Some(Breakpoint {
+            addr,
+            original: (addr as *const u8).read_volatile(),
+        });
+        write_memory(addr, INT3);
+    }
+    Ok(())
+}
+
+fn remove_breakpoint(addr: u64, breakpoints: &mut [Option<Breakpoint>]) -> Result<(), u8> {
+    let slot = breakpoints
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|b| b.addr == addr))
+        .ok_or(EINVAL)?;
+    if let Some(breakpoint) = slot.take() {
+        unsafe { write_memory(addr, breakpoint.original) };
+    }
+    Ok(())
+}
+
+/// Read a byte, showing gdb what was there before we put a breakpoint on it.
+fn read_byte(addr: u64, breakpoints: &[Option<Breakpoint>]) -> u8 {
+    match breakpoints.iter().flatten().find(|b| b.addr == addr) {
+        Some(breakpoint) => breakpoint.original,
+        None => unsafe { (addr as *const u8).read_volatile() },
+    }
+}
+
+/// Write a byte, keeping any breakpoint on it in place.
+fn write_byte(addr: u64, byte: u8, breakpoints: &mut [Option<Breakpoint>]) {
+    match breakpoints.iter_mut().flatten().find(|b| b.addr == addr) {
+        Some(breakpoint) => breakpoint.original = byte,
+        None => unsafe { write_memory(addr, byte) },
+    }
+}
+
+/// Write to memory even if it is mapped read-only, like the kernel's code.
+///
+/// This function is unsafe because the caller must guarantee that `addr` is
+/// mapped and that nothing relies on its old contents.
+unsafe fn write_memory(addr: u64, byte: u8) {
+    let cr0 = Cr0::read();
+    Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+    (addr as *mut u8).write_volatile(byte);
+    Cr0::write(cr0);
+}
+
+/// Whether every byte in `addr..addr + len` is mapped, so touching it won't
+/// page fault from inside the stub.
+fn is_mapped(addr: u64, len: u64) -> bool {
+    let Some(end) = addr.checked_add(len.max(1) - 1) else {
+        return false;
+    };
+    let mut page = addr & !0xFFF;
+    while page <= end {
+        if !is_page_mapped(page) {
+            return false;
+        }
+        match page.checked_add(0x1000) {
+            Some(next) => page = next,
+            None => break,
+        }
+    }
+    true
+}
+
+fn is_page_mapped(addr: u64) -> bool {
+    let Ok(addr) = VirtAddr::try_new(addr) else {
+        return false;
+    };
+    let physical_memory_offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let (mut frame, _) = Cr3::read();
+    for index in indexes {
+        let table_ptr =
+            (physical_memory_offset + frame.start_address().as_u64()) as *const PageTable;
+        let table = unsafe { &*table_ptr };
+        frame = match table[index].frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return false,
+            // A present 2 MiB or 1 GiB page
+            Err(FrameError::HugeFrame) => return true,
+        };
+    }
+    true
+}
+
+fn hex_digit(n: u8) -> u8 {
+    b"0123456789abcdef"[n as usize & 0xF]
+}
+
+fn from_hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|n| n as u8)
+}
+
+/// Parse a big endian hex number like an address or length.
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter()
+        .try_fold(0u64, |n, &c| Some(n << 4 | from_hex_digit(c)? as u64))
+}
+
+/// Parse a little endian hex register value.
+fn parse_hex_le(hex: &[u8]) -> Option<u64> {
+    if !hex.len().is_multiple_of(2) || hex.len() > 16 {
+        return None;
+    }
+    hex.chunks(2)
+        .rev()
+        .try_fold(0u64, |n, pair| Some(n << 8 | parse_hex(pair)?))
+}
+
+/// Parse the `addr,length` arguments of memory and breakpoint packets.
+fn parse_address_length(args: &[u8]) -> Option<(u64, u64)> {
+    let (addr, len) = split_once(args, b',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let i = bytes.iter().position(|&b| b == separator)?;
+    Some((&bytes[..i], &bytes[i + 1..]))
+}
+
+#[test_case]
+fn test_parse_packet_arguments() {
+    assert_eq!(parse_hex(b"ffff8000"), Some(0xffff_8000));
+    assert_eq!(parse_hex_le(b"3412000000000000"), Some(0x1234));
+    assert_eq!(parse_address_length(b"1000,4"), Some((0x1000, 4)));
+    assert_eq!(parse_address_length(b"1000"), None);
+}
@@ -1,53 +1,126 @@
+use crate::stack::{KernelStack, DEFAULT_STACK_SIZE};
+use crate::vma::VmaError;
+use conquer_once::spin::OnceCell;
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// The exceptions with a stack of their own, by IST index.
+const IST_STACKS: [(u16, &str); 3] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault"),
+    (NMI_IST_INDEX, "NMI"),
+    (MACHINE_CHECK_IST_INDEX, "machine check"),
+];
 
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// The segments, in the order `syscall` and `sysret` expect: the kernel's
+/// data segment right after its code segment, and the user data segment
+/// right before the user code segment.
+///   see: https://wiki.osdev.org/SYSENTER#AMD:_SYSCALL.2FSYSRET
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
         (
             gdt,
             Selectors {
                 code_selector,
+                data_selector,
+                user_data_selector,
+                user_code_selector,
                 tss_selector,
             },
         )
     };
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
+/// The selectors of every segment in the GDT.
+pub fn selectors() -> Selectors {
+    GDT.1
 }
 
+// The CPU reads the IST out of the TSS on every interrupt that uses it, so
+// it stays where it is and we change the entries in place once there are
+// guarded stacks to point them at.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+// Until memory management is up the IST entries point at plain statics
+// without guard pages.
+const BOOT_STACK_SIZE: usize = 4096 * 5;
+
+#[repr(align(16))]
+struct BootStacks([[u8; BOOT_STACK_SIZE]; IST_STACKS.len()]);
+
+static mut BOOT_STACKS: BootStacks = BootStacks([[0; BOOT_STACK_SIZE]; IST_STACKS.len()]);
+
+/// The guarded stacks that replaced the boot stacks, kept so they are never
+/// unmapped.
+static IST: OnceCell<[KernelStack; IST_STACKS.len()]> = OnceCell::uninit();
+
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
+    for (i, (index, _)) in IST_STACKS.iter().enumerate() {
+        unsafe {
+            let stack_start = VirtAddr::from_ptr(addr_of!(BOOT_STACKS.0[i]));
+            (*addr_of_mut!(TSS)).interrupt_stack_table[*index as usize] =
+                stack_start + BOOT_STACK_SIZE;
+        }
+    }
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Move every IST entry onto a stack with a guard page below it, so an
+/// overflow in an exception handler faults instead of running into the
+/// statics next to it. Call once the kernel's virtual memory is tracked.
+pub fn init_stacks() -> Result<(), VmaError> {
+    let mut stacks = IST_STACKS.map(|_| None);
+    for (stack, (_, name)) in stacks.iter_mut().zip(IST_STACKS) {
+        *stack = Some(KernelStack::new(DEFAULT_STACK_SIZE, name)?);
+    }
+    IST.try_init_once(|| stacks.map(Option::unwrap))
+        .expect("IST stacks already initialized");
+    let stacks = IST.get().unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for (stack, (index, _)) in stacks.iter().zip(IST_STACKS) {
+            unsafe { (*addr_of_mut!(TSS)).interrupt_stack_table[index as usize] = stack.top() };
+        }
+    });
+    Ok(())
+}
+
+/// Where the CPU switches the stack to when ring 3 is interrupted or makes
+/// a system call.
+pub fn set_kernel_stack(top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe { (*addr_of_mut!(TSS)).privilege_stack_table[0] = top };
+        crate::syscall::set_kernel_stack(top);
+    });
+}
@@ -6,12 +6,13 @@
 // See:
 //   https://wiki.osdev.org/Exceptions
 //   https://wiki.osdev.org/IRQ#Standard_ISA_IRQs
-use crate::hlt_loop;
-use crate::{gdt, println};
+use crate::apic;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
 
 // PIC interrupt vectors are default mapped to 0-7 and 9-15. This conflicts with
 // CPU exceptions and so we must move the base of these vectors to some non used
@@ -23,18 +24,71 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+// Command ports of the two PICs, for the things `ChainedPics` doesn't do.
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+// OCW3 command asking a PIC for its In-Service Register on the next read.
+const PIC_READ_ISR: u8 = 0x0B;
+// Non specific end of interrupt command.
+const PIC_EOI: u8 = 0x20;
+// The secondary PIC is chained to the primary PIC on IRQ2.
+const CASCADE_IRQ: u8 = 2;
+
+/// Number of legacy ISA IRQs.
+pub const IRQ_COUNT: usize = 16;
+
+/// A function a driver registers to run when its IRQ fires.
+/// WARNING Runs inside an interrupt handler.
+/// WARNING Must not block or allocate.
+pub type IrqHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ number is not below `IRQ_COUNT`.
+    InvalidIrq(u8),
+    /// Another handler is already registered for the IRQ.
+    AlreadyRegistered(u8),
+}
+
+// Registered handlers stored as `IrqHandler` function pointers cast to usize,
+// zero when nothing is registered. Atomics rather than a lock so the
+// interrupt handler can never deadlock against `register_irq`.
+static IRQ_HANDLERS: [AtomicUsize; IRQ_COUNT] = [const { AtomicUsize::new(0) }; IRQ_COUNT];
+// How many times each IRQ has fired.
+static IRQ_COUNTS: [AtomicU64; IRQ_COUNT] = [const { AtomicU64::new(0) }; IRQ_COUNT];
+// How many spurious IRQ7s and IRQ15s the PICs have raised.
+static SPURIOUS_IRQ_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// One IDT entry per IRQ that forwards to `dispatch_irq`. The handler itself
+// is looked up at interrupt time so drivers can register after the IDT is
+// loaded.
+const IRQ_TRAMPOLINES: [HandlerFunc; IRQ_COUNT] = [
+    irq_trampoline::<0>,
+    irq_trampoline::<1>,
+    irq_trampoline::<2>,
+    irq_trampoline::<3>,
+    irq_trampoline::<4>,
+    irq_trampoline::<5>,
+    irq_trampoline::<6>,
+    irq_trampoline::<7>,
+    irq_trampoline::<8>,
+    irq_trampoline::<9>,
+    irq_trampoline::<10>,
+    irq_trampoline::<11>,
+    irq_trampoline::<12>,
+    irq_trampoline::<13>,
+    irq_trampoline::<14>,
+    irq_trampoline::<15>,
+];
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        crate::exceptions::install(&mut idt);
+        for (irq, trampoline) in IRQ_TRAMPOLINES.iter().enumerate() {
+            idt[irq_vector(irq as u8) as usize].set_handler_fn(*trampoline);
         }
-        idt[InterruptIndex::Timer as usize].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt[InterruptIndex::ApicSpurious as usize].set_handler_fn(apic_spurious_handler);
         idt
     };
 }
@@ -44,85 +98,191 @@ lazy_static! {
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    // The Local APIC's spurious interrupt vector. The low 4 bits must be set
+    // on older CPUs so use the last vector.
+    ApicSpurious = 0xFF,
 }
 
 pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Remap the PICs out of the way of the CPU exceptions and mask every IRQ
+/// except the cascade. Lines are unmasked as handlers get registered.
+pub fn init_pics() {
+    let mut pics = PICS.lock();
+    unsafe {
+        pics.initialize();
+        pics.write_masks(!(1 << CASCADE_IRQ), 0xFF);
+    }
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    panic!(
-        "EXCEPTION: {:?} DOUBLE FAULT\n{:#?}",
-        error_code, stack_frame
-    );
+/// The IDT vector an IRQ is delivered on, for both the PICs and the APICs.
+pub fn irq_vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // print!(".");
-    // TODO: increment atomic usize to count uptime ticks
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer as u8);
+/// Run `handler` every time `irq` fires and unmask the IRQ.
+/// The end of interrupt is sent after the handler returns.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let slot = IRQ_HANDLERS
+        .get(irq as usize)
+        .ok_or(IrqError::InvalidIrq(irq))?;
+    slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map_err(|_| IrqError::AlreadyRegistered(irq))?;
+    unmask_irq(irq);
+    Ok(())
+}
+
+/// Mask `irq` and remove its handler.
+pub fn unregister_irq(irq: u8) -> Result<(), IrqError> {
+    let slot = IRQ_HANDLERS
+        .get(irq as usize)
+        .ok_or(IrqError::InvalidIrq(irq))?;
+    mask_irq(irq);
+    slot.store(0, Ordering::Release);
+    Ok(())
+}
+
+/// Whether a handler is registered for `irq`.
+pub fn is_registered(irq: u8) -> bool {
+    IRQ_HANDLERS
+        .get(irq as usize)
+        .is_some_and(|slot| slot.load(Ordering::Acquire) != 0)
+}
+
+/// Number of times `irq` has fired since boot, not counting spurious IRQs.
+pub fn irq_count(irq: u8) -> u64 {
+    IRQ_COUNTS
+        .get(irq as usize)
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Number of spurious IRQ7s and IRQ15s raised by the PICs since boot.
+pub fn spurious_irq_count() -> u64 {
+    SPURIOUS_IRQ_COUNT.load(Ordering::Relaxed)
+}
+
+/// Let `irq` through to the CPU.
+pub fn unmask_irq(irq: u8) {
+    if apic::is_enabled() {
+        // The Local APIC timer replaces the PIT
+        if irq != 0 {
+            apic::route_isa_irq(irq, irq_vector(irq));
+        }
+    } else {
+        set_pic_mask(irq, false);
+    }
+}
+
+/// Stop `irq` from reaching the CPU.
+pub fn mask_irq(irq: u8) {
+    if apic::is_enabled() {
+        apic::mask_isa_irq(irq);
+    } else {
+        set_pic_mask(irq, true);
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    // use spin::Mutex;
-    // use x86_64::instructions::port::Port;
-
-    // lazy_static! {
-    //     static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-    //         Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-    //     );
-    // }
-
-    // let mut keyboard = KEYBOARD.lock();
-    // let mut port = Port::new(0x60);
-
-    // let scancode: u8 = unsafe { port.read() };
-    // if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-    //     if let Some(key) = keyboard.process_keyevent(key_event) {
-    //         match key {
-    //             DecodedKey::Unicode(character) => print!("{}", character),
-    //             DecodedKey::RawKey(key) => print!("{:?}", key),
-    //         }
-    //     }
-    // }
-    // unsafe {
-    //     PICS.lock()
-    //         .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
-    // }
-    use x86_64::instructions::port::Port;
-
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode); // new
+fn set_pic_mask(irq: u8, masked: bool) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            let masks = pics.read_masks();
+            let mut bytes = u16::from_le_bytes(masks);
+            if masked {
+                bytes |= 1 << irq;
+            } else {
+                bytes &= !(1 << irq);
+            }
+            let [mask1, mask2] = bytes.to_le_bytes();
+            pics.write_masks(mask1, mask2);
+        }
+    });
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
+extern "x86-interrupt" fn irq_trampoline<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(IRQ);
+}
+
+fn dispatch_irq(irq: u8) {
+    if is_spurious_pic_irq(irq) {
+        SPURIOUS_IRQ_COUNT.fetch_add(1, Ordering::Relaxed);
+        // The primary PIC did see a real IRQ2 from the secondary PIC and
+        // still expects its end of interrupt.
+        if irq == 15 {
+            unsafe { Port::new(PIC_1_COMMAND).write(PIC_EOI) };
+        }
+        return;
+    }
+
+    IRQ_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+    let handler = IRQ_HANDLERS[irq as usize].load(Ordering::Acquire);
+    if handler != 0 {
+        let handler = unsafe { core::mem::transmute::<usize, IrqHandler>(handler) };
+        handler();
     }
+    notify_end_of_interrupt(irq);
+    // Last, the switch only returns here once this thread's turn comes again
+    crate::thread::preempt();
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
+/// The PICs raise IRQ7 or IRQ15 when an IRQ goes away before it could be
+/// delivered. A real one has its bit set in the PIC's In-Service Register.
+/// See: https://wiki.osdev.org/8259_PIC#Spurious_IRQs
+fn is_spurious_pic_irq(irq: u8) -> bool {
+    if apic::is_enabled() {
+        return false;
+    }
+    let command = match irq {
+        7 => PIC_1_COMMAND,
+        15 => PIC_2_COMMAND,
+        _ => return false,
+    };
+    let mut port: Port<u8> = Port::new(command);
+    let in_service = unsafe {
+        port.write(PIC_READ_ISR);
+        port.read()
+    };
+    in_service & (1 << 7) == 0
+}
+
+/// Signal the end of an interrupt to whichever controller delivered it.
+fn notify_end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(irq_vector(irq));
+        }
+    }
+}
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+// The Local APIC raises this when an interrupt goes away before it could be
+// delivered. It must not be acknowledged with an end of interrupt.
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {}
+
+#[test_case]
+fn test_timer_irq_counted() {
+    let before = irq_count(0);
+    x86_64::instructions::hlt();
+    x86_64::instructions::hlt();
+    assert!(irq_count(0) > before);
+}
+
+#[test_case]
+fn test_register_irq() {
+    fn handler() {}
+    // IRQ5 is normally unused
+    assert_eq!(register_irq(5, handler), Ok(()));
+    assert_eq!(
+        register_irq(5, handler),
+        Err(IrqError::AlreadyRegistered(5))
+    );
+    assert!(is_registered(5));
+    assert_eq!(unregister_irq(5), Ok(()));
+    assert!(!is_registered(5));
+    assert_eq!(register_irq(16, handler), Err(IrqError::InvalidIrq(16)));
 }
 
 #[test_case]
@@ -4,17 +4,37 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(allocator_api)]
+#![feature(btreemap_alloc)]
 
+pub mod acpi;
+pub mod address_space;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod console;
+pub mod cow;
+pub mod elf;
+pub mod exceptions;
+pub mod frame_allocator;
+pub mod gdb;
 pub mod gdt;
 pub mod interrupts;
+pub mod log;
 pub mod memory;
+pub mod pit;
 pub mod rtc;
 pub mod serial;
+pub mod stack;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod user;
 pub mod vga_buffer;
+pub mod vma;
 extern crate alloc;
-use crate::memory::BootInfoFrameAllocator;
+use crate::frame_allocator::GlobalFrameAllocator;
 use bootloader::BootInfo;
 use core::panic::PanicInfo;
 use x86_64::VirtAddr;
@@ -28,21 +48,55 @@ entry_point!(test_kernel_main);
 pub fn init(boot_info: &'static BootInfo) {
     // Disable interrupts before we do anything
     x86_64::instructions::interrupts::disable();
+    // Pick where log records go. The serial console has no screen to log to.
+    if console::SERIAL {
+        log::init(
+            log::Sinks::SERIAL | log::Sinks::RING_BUFFER,
+            log::Level::Info,
+        );
+    } else {
+        log::init(log::Sinks::ALL, log::Level::Info);
+    }
     // Setup the interrupt descriptor table to handle interrupts
     interrupts::init_idt();
     // Setup the global descriptor table
     gdt::init();
+    // Get ready for system calls from ring 3
+    syscall::init();
     //
-    unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::init_pics();
+    // Program the timer so we can keep track of uptime
+    pit::init();
+    // Start listening for key presses
+    task::keyboard::init();
     // Enable interrupts
     x86_64::instructions::interrupts::enable();
     // Initialize the heap
     // TODO: put this in an initializer somewhere else
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { frame_allocator::init(&boot_info.memory_map, phys_mem_offset) };
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap().expect("heap initialization failed");
+    // Switch from the legacy PICs to the APICs if the machine has them
+    let apic = memory::with_mapper(|mapper| {
+        apic::init(mapper, &mut GlobalFrameAllocator, phys_mem_offset)
+    });
+    if let Err(err) = apic {
+        warn!("APIC unavailable, falling back to the 8259 PIC: {:?}", err);
+    }
+    // Keep track of what is where in the kernel's address space
+    vma::init(&boot_info.memory_map);
+    // Give the exception handlers stacks with guard pages
+    gdt::init_stacks().expect("failed to map the interrupt stacks");
+    // Set aside room for processes
+    address_space::init().expect("failed to set up address spaces");
+    // Hand breakpoints to gdb on COM2, but not in the tests, where nobody
+    // is attached to answer `test_breakpoint_exception`
+    #[cfg(all(feature = "gdb", not(test)))]
+    unsafe {
+        gdb::init(phys_mem_offset)
+    };
 }
 
 // Trait for wrapping test functions to get some nice output
@@ -78,6 +132,9 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    if let Some(task) = task::current() {
+        serial_println!("while polling task {:?}\n", task);
+    }
     exit_qemu(QemuExitCode::Failed);
     // Just in case qemu doesn't exit, enter infinite halt loop
     hlt_loop();
@@ -120,6 +177,15 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
+/// Run the CPUID instruction for `leaf`.
+pub fn cpuid(leaf: u32) -> core::arch::x86_64::CpuidResult {
+    // `__cpuid` is only unsafe on older toolchains
+    #[allow(unused_unsafe)]
+    unsafe {
+        core::arch::x86_64::__cpuid(leaf)
+    }
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
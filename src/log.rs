@@ -0,0 +1,334 @@
+// Kernel Logging
+// Diagnostics go through the `error!`, `warn!`, `info!`, `debug!` and
+// `trace!` macros rather than straight to the screen. Each record carries a
+// level, the module it came from and the uptime it was logged at:
+//   [    1.234] WARN  greg_os::task::keyboard: scancode queue full
+// and is written to whichever sinks were picked in `init`: the VGA buffer,
+// COM1, and a ring buffer in memory that keeps the most recent records
+// around to be read back later with `dump` or `ring_buffer`.
+// Records are filtered by a global maximum level which can be overridden for
+// individual modules with `set_target_level`.
+// Logging is allowed from interrupt handlers. The VGA writer and serial port
+// may already be locked by the code that was interrupted, so the sinks only
+// ever `try_lock` them. A record that can't get the lock is skipped on that
+// sink, counted, and still kept in the ring buffer, which is only ever
+// locked with interrupts disabled.
+
+use crate::serial::{self, ComPort};
+use crate::vga_buffer;
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// How important a record is, most important first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn from_u8(level: u8) -> Level {
+        match level {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Padded so the messages line up
+        f.pad(match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        })
+    }
+}
+
+/// A set of places records are written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sinks(u8);
+
+impl Sinks {
+    pub const NONE: Sinks = Sinks(0);
+    pub const VGA: Sinks = Sinks(1);
+    pub const SERIAL: Sinks = Sinks(1 << 1);
+    pub const RING_BUFFER: Sinks = Sinks(1 << 2);
+    pub const ALL: Sinks = Sinks(0b111);
+
+    pub fn contains(self, other: Sinks) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Sinks {
+    type Output = Sinks;
+
+    fn bitor(self, other: Sinks) -> Sinks {
+        Sinks(self.0 | other.0)
+    }
+}
+
+/// Where records go until `init` is called.
+const EARLY_SINKS: Sinks = Sinks(Sinks::SERIAL.0 | Sinks::RING_BUFFER.0);
+/// Size of the in-memory log.
+const RING_BUFFER_SIZE: usize = 16 * 1024;
+/// How many modules can have their own level.
+const MAX_TARGET_LEVELS: usize = 8;
+
+static SINKS: AtomicU8 = AtomicU8::new(EARLY_SINKS.0);
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+// Only locked with interrupts disabled
+static TARGET_LEVELS: Mutex<[Option<(&str, Level)>; MAX_TARGET_LEVELS]> =
+    Mutex::new([None; MAX_TARGET_LEVELS]);
+static RING_BUFFER: Mutex<RingBuffer<RING_BUFFER_SIZE>> = Mutex::new(RingBuffer::new());
+// Records a sink had to skip because its lock was held
+static MISSED_VGA: AtomicU64 = AtomicU64::new(0);
+static MISSED_SERIAL: AtomicU64 = AtomicU64::new(0);
+
+/// Pick the sinks and the maximum level to log at.
+pub fn init(sinks: Sinks, max_level: Level) {
+    SINKS.store(sinks.0, Ordering::Relaxed);
+    set_max_level(max_level);
+}
+
+/// Only log records at `level` or more important.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Log records from modules whose path starts with `target` at `level`
+/// instead of the global maximum. The longest matching target wins.
+/// Returns false if there are already too many targets.
+pub fn set_target_level(target: &'static str, level: Level) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut targets = TARGET_LEVELS.lock();
+        let slot = targets
+            .iter_mut()
+            .find(|slot| slot.is_none() || slot.is_some_and(|(t, _)| t == target));
+        match slot {
+            Some(slot) => {
+                *slot = Some((target, level));
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Whether a record at `level` from `target` would be logged.
+pub fn enabled(level: Level, target: &str) -> bool {
+    let max_level = interrupts::without_interrupts(|| {
+        TARGET_LEVELS
+            .lock()
+            .iter()
+            .flatten()
+            .filter(|(prefix, _)| target.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|&(_, level)| level)
+    })
+    .unwrap_or_else(|| Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed)));
+    level <= max_level
+}
+
+struct Record<'a> {
+    uptime: Duration,
+    level: Level,
+    target: &'a str,
+    args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:>5}.{:03}] {:<5} {}: {}",
+            self.uptime.as_secs(),
+            self.uptime.subsec_millis(),
+            self.level,
+            self.target,
+            self.args
+        )
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, target: &str, args: fmt::Arguments) {
+    if !enabled(level, target) {
+        return;
+    }
+    let record = Record {
+        uptime: crate::pit::uptime(),
+        level,
+        target,
+        args,
+    };
+    let sinks = Sinks(SINKS.load(Ordering::Relaxed));
+
+    interrupts::without_interrupts(|| {
+        if sinks.contains(Sinks::RING_BUFFER) {
+            let _ = writeln!(RING_BUFFER.lock(), "{}", record);
+        }
+        if sinks.contains(Sinks::SERIAL) {
+            match serial::port(ComPort::Com1).try_lock() {
+                Some(mut port) => {
+                    report_missed(&mut *port, &MISSED_SERIAL);
+                    let _ = writeln!(port, "{}", record);
+                }
+                None => {
+                    MISSED_SERIAL.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if sinks.contains(Sinks::VGA) {
+            match vga_buffer::WRITER.try_lock() {
+                Some(mut writer) => {
+                    report_missed(&mut *writer, &MISSED_VGA);
+                    let _ = writeln!(writer, "{}", record);
+                }
+                None => {
+                    MISSED_VGA.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    });
+}
+
+/// Let the reader know records were skipped since the last one they saw.
+fn report_missed(sink: &mut impl Write, missed: &AtomicU64) {
+    let count = missed.swap(0, Ordering::Relaxed);
+    if count > 0 {
+        let _ = writeln!(sink, "({} log records missed, see the ring buffer)", count);
+    }
+}
+
+/// A copy of the records in the ring buffer, oldest first.
+pub fn ring_buffer() -> String {
+    let mut contents = String::new();
+    interrupts::without_interrupts(|| RING_BUFFER.lock().read(&mut contents));
+    contents
+}
+
+/// Print the records in the ring buffer to serial.
+pub fn dump() {
+    crate::serial_print!("{}", ring_buffer());
+}
+
+/// Log a record at a level, from the calling module or a given target.
+#[macro_export]
+macro_rules! log {
+    (target: $target:expr, $level:expr, $($arg:tt)+) => {
+        $crate::log::_log($level, $target, format_args!($($arg)+))
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log!(target: module_path!(), $level, $($arg)+)
+    };
+}
+
+/// Log an error, something went wrong.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Error, $($arg)+));
+}
+
+/// Log a warning, something looks wrong but we carried on.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Warn, $($arg)+));
+}
+
+/// Log something interesting.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Info, $($arg)+));
+}
+
+/// Log details only useful when debugging.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Debug, $($arg)+));
+}
+
+/// Log everything, very noisy.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Trace, $($arg)+));
+}
+
+/// Keeps the last `N` bytes of text written to it.
+struct RingBuffer<const N: usize> {
+    buffer: [u8; N],
+    /// Where the next byte goes.
+    head: usize,
+    /// Whether we have gone all the way around and overwritten old text.
+    wrapped: bool,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        RingBuffer {
+            buffer: [0; N],
+            head: 0,
+            wrapped: false,
+        }
+    }
+
+    /// Append the contents to `out`, oldest first.
+    fn read(&self, out: &mut String) {
+        let mut bytes = alloc::vec::Vec::with_capacity(N);
+        if self.wrapped {
+            bytes.extend_from_slice(&self.buffer[self.head..]);
+            // The oldest line has lost its start, skip it
+            let start = bytes.iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+            bytes.drain(..start);
+        }
+        bytes.extend_from_slice(&self.buffer[..self.head]);
+        out.push_str(&String::from_utf8_lossy(&bytes));
+    }
+}
+
+impl<const N: usize> Write for RingBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buffer[self.head] = byte;
+            self.head += 1;
+            if self.head == N {
+                self.head = 0;
+                self.wrapped = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_ring_buffer_keeps_latest_lines() {
+    let mut ring = RingBuffer::<16>::new();
+    write!(ring, "first\nsecond\nthird\n").unwrap();
+    let mut contents = String::new();
+    ring.read(&mut contents);
+    assert_eq!(contents, "second\nthird\n");
+}
+
+#[test_case]
+fn test_log_to_ring_buffer() {
+    crate::warn!("test_log_to_ring_buffer record");
+    let contents = ring_buffer();
+    let line = contents.lines().last().unwrap();
+    assert!(line.ends_with("WARN  greg_os::log: test_log_to_ring_buffer record"));
+}
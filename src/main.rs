@@ -14,7 +14,7 @@ extern crate alloc;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use greg_os::println;
-use greg_os::task::{executor::Executor, keyboard, Task};
+use greg_os::task::executor::Executor;
 
 // The entry point function to our kernel
 entry_point!(kernel_main);
@@ -27,7 +27,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // Asynchronous runtime executor
     let mut executor = Executor::new();
-    executor.spawn(Task::new(resume::main()));
+    // Without it there is nothing to show, but the kernel keeps running
+    if let Err(err) = executor.try_spawn(resume::main()) {
+        greg_os::warn!("no memory for the resume viewer: {:?}", err);
+    }
     executor.run();
 }
 
@@ -36,6 +39,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    if let Some(task) = greg_os::task::current() {
+        println!("while polling task {:?}", task);
+    }
+    greg_os::backtrace::panic_backtrace();
     greg_os::hlt_loop();
 }
 
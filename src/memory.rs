@@ -1,11 +1,24 @@
-use x86_64::structures::paging::OffsetPageTable;
+use crate::frame_allocator::GlobalFrameAllocator;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{MappedFrame, Translate, TranslateResult, UnmapError};
+use x86_64::structures::paging::{OffsetPageTable, PageSize, PageTableFlags, Size1GiB, Size2MiB};
 use x86_64::{structures::paging::PageTable, VirtAddr};
 use x86_64::{
-    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PhysFrame, Size4KiB},
     PhysAddr,
 };
 
-/// Initialize a new OffsetPageTable.
+// The kernel's page table mapper, kept around after `init` so the heap and
+// drivers can map memory whenever they need to.
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+// Where the bootloader mapped all of physical memory.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Initialize the kernel's OffsetPageTable, reachable through `with_mapper`.
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
@@ -13,9 +26,38 @@ use x86_64::{
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 /// # Safety
 ///    There is none. Must follow the rules.
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    // Make the kernel's writes to read-only pages fault too, for copy-on-write
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    // Honour NO_EXECUTE, without it the bit is reserved and every page runs
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    *MAPPER.lock() = Some(OffsetPageTable::new(level_4_table, physical_memory_offset));
+}
+
+/// Where all of physical memory is mapped in virtual memory.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Run `f` with the kernel's page table mapper, with interrupts off so the
+/// thread holding it can't be preempted. Otherwise heap growth and page
+/// faults in other threads would find it taken and fail.
+/// WARNING Don't allocate on the heap inside `f`, growing the heap needs the
+/// mapper too and will fail.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        f(mapper.as_mut().expect("memory::init has not been called"))
+    })
+}
+
+/// Like `with_mapper` but gives up and returns `None` if the mapper is
+/// already in use, or not set up yet, rather than waiting.
+pub fn try_with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> Option<R> {
+    let mut mapper = MAPPER.try_lock()?;
+    Some(f(mapper.as_mut()?))
 }
 
 // /// Creates an example mapping for the given page to frame `0xb8000`.
@@ -36,50 +78,166 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
 //     map_to_result.expect("map_to failed").flush();
 // }
 
-use bootloader::bootinfo::MemoryMap;
-use bootloader::bootinfo::MemoryRegionType;
-
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    /// # Safety
-    ///    There is none. Must follow the rules.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+/// Map a page of memory mapped I/O registers at `page` to the physical
+/// `frame` the device decodes. The mapping skips the caches so every read and
+/// write actually reaches the device.
+///
+/// This function is unsafe because the caller must guarantee that `frame`
+/// really belongs to a device and that `page` is otherwise unused.
+/// # Safety
+///    There is none. Must follow the rules.
+pub unsafe fn map_mmio(
+    page: Page,
+    frame: PhysFrame,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::WRITE_THROUGH;
+    mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    Ok(())
+}
+
+/// Whether the CPU can map 1 GiB pages. Many can't, QEMU's default CPU
+/// included, and trying to is a page fault waiting to happen.
+pub fn supports_1gib_pages() -> bool {
+    // The extended processor features leaf, bit 26 of edx is Page1GB
+    let cpuid = crate::cpuid(0x8000_0001);
+    cpuid.edx & (1 << 26) != 0
+}
+
+/// Map `page` to `frame` in the kernel's page table, allocating any page
+/// tables needed on the way. 2 MiB and 1 GiB pages take a single entry in the
+/// P2 or P3 table so cover a lot of memory with one TLB entry. Check
+/// `supports_1gib_pages` before mapping 1 GiB pages.
+///
+/// This function is unsafe because the caller must guarantee that `frame`
+/// isn't in use by anything else that `page` would clash with.
+/// # Safety
+///    There is none. Must follow the rules.
+pub unsafe fn map_page<S: PageSize>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<S>>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    with_mapper(|mapper| {
+        mapper
+            .map_to(page, frame, flags, &mut GlobalFrameAllocator)?
+            .flush();
+        Ok(())
+    })
+}
+
+/// Remove `page` from the kernel's page table and return the frame it was
+/// mapped to, which is left for the caller to free.
+pub fn unmap_page<S: PageSize>(page: Page<S>) -> Result<PhysFrame<S>, UnmapError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    with_mapper(|mapper| {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/// Map the `size` bytes of physical memory at `phys` to `virt` in the
+/// kernel's page table, eg. a framebuffer. The largest pages that both
+/// addresses line up for are used so big regions take few entries.
+///
+/// This function is unsafe for the same reasons as `map_page`.
+/// # Safety
+///    There is none. Must follow the rules.
+pub unsafe fn map_physical_region(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let huge_1gib = supports_1gib_pages();
+    with_mapper(|mapper| {
+        let mut offset = 0;
+        while offset < size {
+            let (virt, phys) = (virt + offset, phys + offset);
+            let fits = |page_size: u64| {
+                virt.is_aligned(page_size)
+                    && phys.is_aligned(page_size)
+                    && size - offset >= page_size
+            };
+            offset += if huge_1gib && fits(Size1GiB::SIZE) {
+                map_one::<Size1GiB>(mapper, virt, phys, flags)?
+            } else if fits(Size2MiB::SIZE) {
+                map_one::<Size2MiB>(mapper, virt, phys, flags)?
+            } else {
+                map_one::<Size4KiB>(mapper, virt, phys, flags)?
+            };
         }
-    }
+        Ok(())
+    })
+}
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+/// Map one page of size `S` at `virt`, which must be aligned for it, and
+/// return how many bytes that covered.
+unsafe fn map_one<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<u64, MapToError<Size4KiB>>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(virt);
+    let frame = PhysFrame::<S>::containing_address(phys);
+    match mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(S::SIZE)
+        }
+        // Report everything in terms of 4 KiB pages so the sizes can share
+        // an error type
+        Err(MapToError::FrameAllocationFailed) => Err(MapToError::FrameAllocationFailed),
+        Err(MapToError::ParentEntryHugePage) => Err(MapToError::ParentEntryHugePage),
+        Err(MapToError::PageAlreadyMapped(frame)) => Err(MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(frame.start_address()),
+        )),
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+/// Unmap the `size` bytes at `virt` from the kernel's page table, whatever
+/// size of pages they were mapped with. The frames aren't freed.
+pub fn unmap_physical_region(virt: VirtAddr, size: u64) {
+    with_mapper(|mapper| {
+        let end = virt + size;
+        let mut addr = virt;
+        while addr < end {
+            let page_size = match mapper.translate(addr) {
+                TranslateResult::Mapped { frame, .. } => match frame {
+                    MappedFrame::Size4KiB(_) => unmap_one::<Size4KiB>(mapper, addr),
+                    MappedFrame::Size2MiB(_) => unmap_one::<Size2MiB>(mapper, addr),
+                    MappedFrame::Size1GiB(_) => unmap_one::<Size1GiB>(mapper, addr),
+                },
+                _ => Size4KiB::SIZE,
+            };
+            // Carry on from the start of the next page
+            addr = (addr + 1u64).align_up(page_size);
+        }
+    })
+}
+
+/// Unmap the page of size `S` containing `addr` and return its size.
+fn unmap_one<S: PageSize>(mapper: &mut OffsetPageTable<'static>, addr: VirtAddr) -> u64
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    if let Ok((_, flush)) = mapper.unmap(Page::<S>::containing_address(addr)) {
+        flush.flush();
     }
+    S::SIZE
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -137,7 +295,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     let mut frame = level_4_table_frame;
 
     // traverse the multi-level page table
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // convert the frame into a page table reference
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -148,10 +306,69 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            // A huge page ends the walk early, the rest of the address is
+            // the offset into it
+            Err(FrameError::HugeFrame) => {
+                let page_size = match level {
+                    1 => Size1GiB::SIZE,
+                    2 => Size2MiB::SIZE,
+                    // The same bit means PAT in a P1 entry
+                    3 => Size4KiB::SIZE,
+                    // and is reserved in a P4 entry
+                    _ => return None,
+                };
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
         };
     }
 
     // calculate the physical address by adding the page offset
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
+
+#[test_case]
+fn test_translate_huge_pages() {
+    // The bootloader maps physical memory with huge pages
+    let addr = physical_memory_offset() + 0x20_1234u64;
+    let phys = unsafe { translate_addr(addr, physical_memory_offset()) };
+    assert_eq!(phys, Some(PhysAddr::new(0x20_1234)));
+}
+
+#[test_case]
+fn test_map_2mib_page() {
+    use crate::vma::{self, RegionKind};
+    use x86_64::structures::paging::mapper::CleanUp;
+    use x86_64::structures::paging::FrameDeallocator;
+
+    let frame: PhysFrame<Size2MiB> = GlobalFrameAllocator.allocate_frame().unwrap();
+    let region = vma::allocate(
+        Size2MiB::SIZE,
+        Size2MiB::SIZE,
+        RegionKind::Other,
+        "test 2 MiB page",
+    )
+    .unwrap();
+    let page = Page::<Size2MiB>::from_start_address(region.start).unwrap();
+    // A region that used to be here may have left an empty P1 table behind
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(region.start),
+        Page::containing_address(region.end() - 1u64),
+    );
+    with_mapper(|mapper| unsafe { mapper.clean_up_addr_range(pages, &mut GlobalFrameAllocator) });
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { map_page(page, frame, flags).unwrap() };
+
+    let addr = page.start_address() + 0x10_0008u64;
+    let phys = unsafe { translate_addr(addr, physical_memory_offset()) };
+    assert_eq!(phys, Some(frame.start_address() + 0x10_0008u64));
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(0xfeed) };
+    let through_phys = physical_memory_offset() + phys.unwrap().as_u64();
+    assert_eq!(
+        unsafe { through_phys.as_ptr::<u64>().read_volatile() },
+        0xfeed
+    );
+
+    assert_eq!(unmap_page(page).ok(), Some(frame));
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    vma::release(region.start).unwrap();
+}
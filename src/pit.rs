@@ -0,0 +1,98 @@
+// Programmable Interval Timer
+// The PIT (Intel 8253/8254) is an oscillator running at ~1.193182 MHz that
+// feeds three counters. Channel 0 is wired to IRQ0 and fires an interrupt
+// every time its counter reaches zero. By loading the counter with a divisor
+// we pick how often the timer interrupt fires.
+//   see: https://wiki.osdev.org/Programmable_Interval_Timer
+// Every timer interrupt increments `TICKS`, which gives us a monotonic clock
+// that starts at zero when the kernel boots. Unlike the RTC it can't tell us
+// what time it is, only how much time has passed.
+// When the APICs are in use the Local APIC timer is calibrated to tick at the
+// same rate and calls `tick` instead, see `apic::init`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+/// The frequency of the PIT's oscillator in Hz.
+const BASE_FREQUENCY: u64 = 1_193_182;
+/// How many timer interrupts we want per second.
+pub const TICK_FREQUENCY: u64 = 1000;
+/// The value loaded into channel 0's counter to get `TICK_FREQUENCY`.
+const DIVISOR: u64 = BASE_FREQUENCY / TICK_FREQUENCY;
+
+/// Data port for channel 0, the channel wired to IRQ0.
+const CHANNEL_0_PORT: u16 = 0x40;
+/// Write only port for selecting a channel and its operating mode.
+const COMMAND_PORT: u16 = 0x43;
+
+/// Command byte: channel 0, access mode lobyte/hibyte, mode 3 (square wave
+/// generator), binary counting.
+///   0b00_11_011_0
+///     ^  ^  ^   ^ binary mode
+///     |  |  ^ mode 3
+///     |  ^ lobyte/hibyte
+///     ^ channel 0
+const CHANNEL_0_SQUARE_WAVE: u8 = 0b0011_0110;
+
+/// Number of timer interrupts since `init` was called.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The PIT's channel 0 is wired to IRQ0.
+const IRQ: u8 = 0;
+
+/// Program channel 0 to fire IRQ0 at `TICK_FREQUENCY` and start counting.
+/// Should be called with interrupts disabled.
+pub fn init() {
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut data: Port<u8> = Port::new(CHANNEL_0_PORT);
+    unsafe {
+        command.write(CHANNEL_0_SQUARE_WAVE);
+        // The divisor is written low byte first then high byte.
+        data.write((DIVISOR & 0xFF) as u8);
+        data.write(((DIVISOR >> 8) & 0xFF) as u8);
+    }
+    crate::interrupts::register_irq(IRQ, tick).expect("timer IRQ already registered");
+}
+
+/// WARNING Called by the timer interrupt handler.
+/// WARNING Must not block or allocate.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    crate::thread::tick();
+}
+
+/// Number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time elapsed since boot.
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// Convert a number of ticks into a `Duration`.
+/// The divisor doesn't divide the base frequency evenly so a tick is slightly
+/// longer than 1ms. Do the math against the real frequency so we don't drift.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * DIVISOR as u128 * 1_000_000_000 / BASE_FREQUENCY as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Convert a `Duration` into a number of ticks, rounding up so that waiting
+/// for that many ticks never waits less than `duration`.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks =
+        (duration.as_nanos() * BASE_FREQUENCY as u128).div_ceil(DIVISOR as u128 * 1_000_000_000);
+    ticks as u64
+}
+
+#[test_case]
+fn test_uptime_advances() {
+    let start = uptime();
+    // Each timer interrupt wakes us from `hlt`
+    x86_64::instructions::hlt();
+    x86_64::instructions::hlt();
+    assert!(uptime() > start);
+}
@@ -1,7 +1,7 @@
-use crate::keyboard::ScancodeStream;
 use futures_util::stream::StreamExt;
+use greg_os::console::{self, KeyStream};
 use greg_os::{print, println, vga_buffer};
-use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+use pc_keyboard::{DecodedKey, KeyCode};
 
 enum States {
     Home,
@@ -9,76 +9,81 @@ enum States {
 }
 
 pub async fn main() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
-    vga_buffer::disable_cursor();
-    vga_buffer::print_logo();
+    let mut keys = KeyStream::new();
+    console::disable_cursor();
+    console::print_logo();
     let mut state = States::Home;
     let mut cursor_x = 0;
     let mut cursor_y = vga_buffer::BUFFER_HEIGHT - 1;
     let mut screen_top = 0;
     let num_lines = TEXT.lines().count();
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match state {
-                    States::Home => {
-                        vga_buffer::WRITER.lock().clear_screen();
-                        vga_buffer::enable_cursor();
-                        vga_buffer::move_cursor(cursor_x, cursor_y);
-                        state = States::Resume;
-                        render_resume(screen_top);
+    while let Some(key) = keys.next().await {
+        if let DecodedKey::RawKey(KeyCode::F12) = key {
+            // Break into gdb from wherever we are
+            greg_os::gdb::breakpoint();
+            continue;
+        }
+        if let DecodedKey::RawKey(KeyCode::F11) = key {
+            // Print the heap's statistics to serial
+            greg_os::allocator::stats::dump();
+            continue;
+        }
+        match state {
+            States::Home => {
+                console::clear_screen();
+                console::enable_cursor();
+                console::move_cursor(cursor_x, cursor_y);
+                state = States::Resume;
+                render_resume(screen_top);
+            }
+            States::Resume => match key {
+                DecodedKey::Unicode(character) => {
+                    if character == (0x1b as char) {
+                        // Escape
+                        console::clear_screen();
+                        state = States::Home;
+                        console::disable_cursor();
+                        console::print_logo();
                     }
-                    States::Resume => match key {
-                        DecodedKey::Unicode(character) => {
-                            if character == (0x1b as char) {
-                                // Escape
-                                vga_buffer::WRITER.lock().clear_screen();
-                                state = States::Home;
-                                vga_buffer::disable_cursor();
-                                vga_buffer::print_logo();
-                            }
-                        }
-                        DecodedKey::RawKey(KeyCode::ArrowDown) => {
-                            cursor_y = (cursor_y + 1).min(vga_buffer::BUFFER_HEIGHT - 1);
-                            let mut new_screen_top = screen_top;
-                            if cursor_y == vga_buffer::BUFFER_HEIGHT - 1 {
-                                new_screen_top = (screen_top + 1).min(num_lines)
-                            }
-                            if new_screen_top != screen_top {
-                                screen_top = new_screen_top;
-                                if screen_top < num_lines - vga_buffer::BUFFER_HEIGHT {
-                                    add_line(screen_top + vga_buffer::BUFFER_HEIGHT - 1)
-                                }
-                            }
-                            vga_buffer::move_cursor(cursor_x, cursor_y);
-                        }
-                        DecodedKey::RawKey(KeyCode::ArrowUp) => {
-                            cursor_y = (cursor_y.saturating_sub(1)).max(0);
-                            let mut new_screen_top = screen_top;
-                            if cursor_y == 0 {
-                                new_screen_top = screen_top.saturating_sub(1);
-                            }
-                            if new_screen_top != screen_top {
-                                screen_top = new_screen_top;
-                                sub_line(screen_top);
-                            }
-
-                            vga_buffer::move_cursor(cursor_x, cursor_y);
-                        }
-                        DecodedKey::RawKey(KeyCode::ArrowRight) => {
-                            cursor_x = (cursor_x + 1).min(vga_buffer::BUFFER_WIDTH - 1);
-                            vga_buffer::move_cursor(cursor_x, cursor_y);
-                        }
-                        DecodedKey::RawKey(KeyCode::ArrowLeft) => {
-                            cursor_x = (cursor_x.saturating_sub(1)).max(0);
-                            vga_buffer::move_cursor(cursor_x, cursor_y);
+                }
+                DecodedKey::RawKey(KeyCode::ArrowDown) => {
+                    cursor_y = (cursor_y + 1).min(vga_buffer::BUFFER_HEIGHT - 1);
+                    let mut new_screen_top = screen_top;
+                    if cursor_y == vga_buffer::BUFFER_HEIGHT - 1 {
+                        new_screen_top = (screen_top + 1).min(num_lines)
+                    }
+                    if new_screen_top != screen_top {
+                        screen_top = new_screen_top;
+                        if screen_top < num_lines - vga_buffer::BUFFER_HEIGHT {
+                            add_line(screen_top + vga_buffer::BUFFER_HEIGHT - 1)
                         }
-                        _ => {} // DecodedKey::RawKey(key) => print!("{:?}", key),
-                    },
+                    }
+                    console::move_cursor(cursor_x, cursor_y);
                 }
-            }
+                DecodedKey::RawKey(KeyCode::ArrowUp) => {
+                    cursor_y = (cursor_y.saturating_sub(1)).max(0);
+                    let mut new_screen_top = screen_top;
+                    if cursor_y == 0 {
+                        new_screen_top = screen_top.saturating_sub(1);
+                    }
+                    if new_screen_top != screen_top {
+                        screen_top = new_screen_top;
+                        sub_line(screen_top);
+                    }
+
+                    console::move_cursor(cursor_x, cursor_y);
+                }
+                DecodedKey::RawKey(KeyCode::ArrowRight) => {
+                    cursor_x = (cursor_x + 1).min(vga_buffer::BUFFER_WIDTH - 1);
+                    console::move_cursor(cursor_x, cursor_y);
+                }
+                DecodedKey::RawKey(KeyCode::ArrowLeft) => {
+                    cursor_x = (cursor_x.saturating_sub(1)).max(0);
+                    console::move_cursor(cursor_x, cursor_y);
+                }
+                _ => {} // DecodedKey::RawKey(key) => print!("{:?}", key),
+            },
         }
     }
 }
@@ -99,9 +104,7 @@ fn add_line(line: usize) {
 }
 
 fn sub_line(line: usize) {
-    vga_buffer::WRITER
-        .lock()
-        .pre_write_line(TEXT.lines().nth(line + 1).unwrap_or(""));
+    console::pre_write_line(TEXT.lines().nth(line + 1).unwrap_or(""));
 }
 
 pub static TEXT: &str = r"
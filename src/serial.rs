@@ -1,13 +1,183 @@
+// Serial Ports
+// The PC has up to four 16550 compatible UARTs at fixed I/O ports. COM1 and
+// COM3 share IRQ4, COM2 and COM4 share IRQ3.
+//   see: https://wiki.osdev.org/Serial_Ports
+// Output is polled: we wait for the transmit register to empty and write the
+// next byte. Input is interrupt driven once a `SerialStream` is opened on a
+// port, the IRQ handler drains the UART's receive FIFO into a lock-free queue
+// the same way `task::keyboard` queues scancodes.
+use conquer_once::spin::OnceCell;
+use core::{
+    fmt,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
 use lazy_static::lazy_static;
 use spin::Mutex;
-use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+/// The UART's clock divided by 16, the fastest it can go.
+pub const MAX_BAUD: u32 = 115_200;
+/// What every port is set up with until told otherwise.
+pub const DEFAULT_BAUD: u32 = 38_400;
+
+// Register offsets from the port's base
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+// With the divisor latch bit set the first two registers hold the divisor
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+
+const LINE_CONTROL_DIVISOR_LATCH: u8 = 1 << 7;
+// 8 data bits, no parity, one stop bit
+const LINE_CONTROL_8N1: u8 = 0b11;
+// Enable and clear both FIFOs, interrupt when 14 bytes are waiting
+const FIFO_ENABLE_CLEAR_14: u8 = 0xC7;
+// DTR, RTS and OUT2, which gates the UART's interrupt line on a PC
+const MODEM_CONTROL_DTR_RTS_OUT2: u8 = 0x0B;
+const INTERRUPT_DATA_AVAILABLE: u8 = 1;
+const LINE_STATUS_DATA_READY: u8 = 1;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// The four standard serial ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    /// The I/O port of its first register.
+    pub fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    /// The ISA IRQ it raises.
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+}
+
+/// A 16550 UART.
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// Create a handle to the UART at I/O port `base`. Call `init` before
+    /// using it.
+    ///
+    /// This function is unsafe because the caller must guarantee that a UART
+    /// lives at `base` and nothing else is driving it.
+    /// # Safety
+    ///    There is none. Must follow the rules.
+    pub const unsafe fn new(base: u16) -> Self {
+        SerialPort { base }
+    }
+
+    /// Set the port up for 8N1 at `baud` with FIFOs and no interrupts.
+    pub fn init(&mut self, baud: u32) {
+        self.write_register(INTERRUPT_ENABLE, 0);
+        self.set_baud(baud);
+        self.write_register(LINE_CONTROL, LINE_CONTROL_8N1);
+        self.write_register(FIFO_CONTROL, FIFO_ENABLE_CLEAR_14);
+        self.write_register(MODEM_CONTROL, MODEM_CONTROL_DTR_RTS_OUT2);
+    }
+
+    /// Change the baud rate. The UART can only divide `MAX_BAUD` by a whole
+    /// number so rates that don't divide it evenly are rounded up.
+    pub fn set_baud(&mut self, baud: u32) {
+        let divisor = (MAX_BAUD / baud.clamp(1, MAX_BAUD)).min(u16::MAX as u32) as u16;
+        let line_control = self.read_register(LINE_CONTROL);
+        self.write_register(LINE_CONTROL, line_control | LINE_CONTROL_DIVISOR_LATCH);
+        let [low, high] = divisor.to_le_bytes();
+        self.write_register(DIVISOR_LOW, low);
+        self.write_register(DIVISOR_HIGH, high);
+        self.write_register(LINE_CONTROL, line_control);
+    }
+
+    /// Raise the port's IRQ whenever a byte arrives.
+    pub fn enable_receive_interrupt(&mut self) {
+        self.write_register(INTERRUPT_ENABLE, INTERRUPT_DATA_AVAILABLE);
+    }
+
+    /// Wait for room in the transmitter and send `byte`.
+    pub fn send(&mut self, byte: u8) {
+        while self.read_register(LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write_register(DATA, byte);
+    }
+
+    /// Take a received byte if there is one.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.read_register(LINE_STATUS) & LINE_STATUS_DATA_READY != 0 {
+            Some(self.read_register(DATA))
+        } else {
+            None
+        }
+    }
+
+    /// Wait for a byte to arrive.
+    pub fn receive(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_receive() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn read_register(&mut self, offset: u16) -> u8 {
+        unsafe { Port::new(self.base + offset).read() }
+    }
+
+    fn write_register(&mut self, offset: u16, value: u8) {
+        unsafe { Port::new(self.base + offset).write(value) }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
+    static ref PORTS: [Mutex<SerialPort>; 4] = ComPort::ALL.map(|com| {
+        let mut serial_port = unsafe { SerialPort::new(com.base()) };
+        serial_port.init(DEFAULT_BAUD);
         Mutex::new(serial_port)
-    };
+    });
+}
+
+/// The shared handle to a serial port, set up at `DEFAULT_BAUD` on first use.
+/// COM2 belongs to the gdb stub when it is enabled.
+pub fn port(com: ComPort) -> &'static Mutex<SerialPort> {
+    &PORTS[com as usize]
 }
 
 #[doc(hidden)]
@@ -15,7 +185,7 @@ pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
     interrupts::without_interrupts(|| {
-        SERIAL1
+        port(ComPort::Com1)
             .lock()
             .write_fmt(args)
             .expect("Printing to serial failed");
@@ -38,3 +208,110 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Wakers of the tasks waiting on each port's input
+static WAKERS: [AtomicWaker; 4] = [const { AtomicWaker::new() }; 4];
+/// Bytes received on each port that haven't been read yet
+static INPUT_QUEUES: [OnceCell<ArrayQueue<u8>>; 4] = [const { OnceCell::uninit() }; 4];
+/// The max number of received bytes we can have in a queue
+const INPUT_QUEUE_LENGTH: usize = 256;
+/// Bytes thrown away because nobody read them fast enough
+static DROPPED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of received bytes dropped because an input queue was full.
+pub fn dropped_bytes() -> u64 {
+    DROPPED_BYTES.load(Ordering::Relaxed)
+}
+
+/// The bytes received on a serial port.
+pub struct SerialStream {
+    com: ComPort,
+}
+
+impl SerialStream {
+    /// Start receiving on `com`. Only one stream can be opened per port.
+    pub fn new(com: ComPort) -> Self {
+        INPUT_QUEUES[com as usize]
+            .try_init_once(|| ArrayQueue::new(INPUT_QUEUE_LENGTH))
+            .expect("SerialStream::new should only be called once per port");
+        let handler = match com.irq() {
+            4 => irq4_handler,
+            _ => irq3_handler,
+        };
+        // The other port on the IRQ may have registered it already
+        match crate::interrupts::register_irq(com.irq(), handler) {
+            Ok(()) | Err(crate::interrupts::IrqError::AlreadyRegistered(_)) => {}
+            Err(err) => panic!("failed to register serial IRQ: {:?}", err),
+        }
+        port(com).lock().enable_receive_interrupt();
+        SerialStream { com }
+    }
+
+    /// Whether more bytes are already waiting, so the next poll won't block.
+    pub fn has_pending(&self) -> bool {
+        INPUT_QUEUES[self.com as usize]
+            .try_get()
+            .is_ok_and(|queue| !queue.is_empty())
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = INPUT_QUEUES[self.com as usize]
+            .try_get()
+            .expect("serial input queue not initialized");
+
+        // fast path
+        if let Ok(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKERS[self.com as usize].register(cx.waker());
+
+        match queue.pop() {
+            Ok(byte) => {
+                WAKERS[self.com as usize].take();
+                Poll::Ready(Some(byte))
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+/// WARNING Runs inside an interrupt handler.
+/// WARNING Must not block or allocate.
+fn irq4_handler() {
+    receive_bytes(ComPort::Com1);
+    receive_bytes(ComPort::Com3);
+}
+
+/// WARNING Runs inside an interrupt handler.
+/// WARNING Must not block or allocate.
+fn irq3_handler() {
+    receive_bytes(ComPort::Com2);
+    receive_bytes(ComPort::Com4);
+}
+
+/// Drain the UART's receive FIFO into its input queue.
+/// Reads the registers directly rather than through `port` so the handler
+/// can't deadlock against whoever holds its lock.
+fn receive_bytes(com: ComPort) {
+    let Ok(queue) = INPUT_QUEUES[com as usize].try_get() else {
+        return;
+    };
+    let mut line_status: Port<u8> = Port::new(com.base() + LINE_STATUS);
+    let mut data: Port<u8> = Port::new(com.base() + DATA);
+    let mut received = false;
+    while unsafe { line_status.read() } & LINE_STATUS_DATA_READY != 0 {
+        let byte = unsafe { data.read() };
+        if queue.push(byte).is_err() {
+            DROPPED_BYTES.fetch_add(1, Ordering::Relaxed);
+        }
+        received = true;
+    }
+    if received {
+        WAKERS[com as usize].wake();
+    }
+}
@@ -0,0 +1,92 @@
+// Kernel Stacks
+// Every stack the kernel runs on, apart from the one the bootloader gave us,
+// is mapped in a region of its own with an unmapped guard page below it.
+// Stacks grow down, so running off the bottom of one touches the guard and
+// page faults instead of quietly overwriting whatever happens to be next.
+//   see: https://wiki.osdev.org/Stack
+// The fault can't be handled on the stack that overflowed, there is no room
+// left to push the exception frame, so the CPU escalates it to a double
+// fault. That one runs on a stack of its own from the interrupt stack table,
+// where `overflowed_stack` turns the faulting address back into the name of
+// the stack it belongs to.
+//   see: https://os.phil-opp.com/double-fault-exceptions/#kernel-stack-overflow
+use crate::vma::{self, Region, RegionKind, VmaError};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// Size of a stack if you don't have a reason to pick another.
+pub const DEFAULT_STACK_SIZE: u64 = 4096 * 5;
+/// Unmapped space below every stack.
+pub const STACK_GUARD_SIZE: u64 = 4096;
+
+/// A mapped stack with a guard page below it. Unmapped again when dropped.
+#[derive(Debug)]
+pub struct KernelStack {
+    region: Region,
+}
+
+impl KernelStack {
+    /// Map a stack of at least `size` bytes. `name` is what gets reported if
+    /// it ever overflows.
+    pub fn new(size: u64, name: &'static str) -> Result<Self, VmaError> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let region =
+            vma::map_guarded_region(size, STACK_GUARD_SIZE, flags, RegionKind::Stack, name)?;
+        Ok(KernelStack { region })
+    }
+
+    /// The address to load into RSP, just past the highest usable byte.
+    pub fn top(&self) -> VirtAddr {
+        self.region.end()
+    }
+
+    /// The lowest usable address, right above the guard.
+    pub fn bottom(&self) -> VirtAddr {
+        self.region.start + self.region.guard
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.region.name
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        vma::unmap_region(self.region.start).expect("kernel stack was already unmapped");
+    }
+}
+
+/// The name of the stack whose guard page `addr` is in, if any.
+/// Doesn't wait for locks so it is safe to call from exception handlers.
+pub fn guard_hit(addr: VirtAddr) -> Option<&'static str> {
+    let region = vma::try_region_containing(addr)?;
+    (region.kind == RegionKind::Stack && region.in_guard(addr)).then_some(region.name)
+}
+
+/// The name of the stack that overflowed if the last page fault was caused
+/// by touching a guard page.
+pub fn overflowed_stack() -> Option<&'static str> {
+    guard_hit(VirtAddr::try_new(Cr2::read_raw()).ok()?)
+}
+
+#[test_case]
+fn test_guard_hit() {
+    let stack = KernelStack::new(DEFAULT_STACK_SIZE, "test stack").unwrap();
+    assert_eq!(stack.top() - stack.bottom(), DEFAULT_STACK_SIZE);
+    assert_eq!(guard_hit(stack.bottom() - 8u64), Some("test stack"));
+    assert_eq!(
+        guard_hit(stack.bottom() - STACK_GUARD_SIZE),
+        Some("test stack")
+    );
+    assert_eq!(guard_hit(stack.bottom()), None);
+    assert_eq!(guard_hit(stack.top() - 8u64), None);
+    // The whole usable range is mapped
+    unsafe {
+        stack.bottom().as_mut_ptr::<u64>().write_volatile(1);
+        (stack.top() - 8u64).as_mut_ptr::<u64>().write_volatile(2);
+    }
+    let bottom = stack.bottom();
+    drop(stack);
+    assert_eq!(vma::region_containing(bottom), None);
+}
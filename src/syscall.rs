@@ -0,0 +1,257 @@
+// System Calls
+// User code asks the kernel for things with the `syscall` instruction. It
+// jumps to the address in the LSTAR MSR in ring 0 with the code segment from
+// STAR, clears the RFLAGS bits in SFMASK, and leaves the user's RIP in RCX
+// and RFLAGS in R11 for `sysret` to go back with. It doesn't switch stacks,
+// so the entry stub does that first, onto the same kernel stack interrupts
+// from ring 3 use.
+//   see: https://wiki.osdev.org/SYSENTER#AMD:_SYSCALL.2FSYSRET
+// The ABI is the same as Linux's: the number in RAX, up to six arguments in
+// RDI, RSI, RDX, R10, R8 and R9, and the result back in RAX. Failures are
+// negative `SyscallError`s. Only RCX and R11 are clobbered. Numbers are
+// never reused, new calls go on the end of the table.
+//   see: https://man7.org/linux/man-pages/man2/syscall.2.html
+use crate::address_space::{USER_END, USER_START};
+use crate::serial::{self, ComPort};
+use crate::{gdt, pit, serial_print, user};
+use core::arch::global_asm;
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+/// `write(fd, buf, len)`, returns how many bytes were written.
+/// fd 1 is the console and fd 2 is serial.
+pub const SYS_WRITE: u64 = 0;
+/// `read(fd, buf, len)`, returns how many bytes were read, maybe 0.
+/// fd 0 is whatever has arrived on COM1.
+pub const SYS_READ: u64 = 1;
+/// `exit(code)`, doesn't return.
+pub const SYS_EXIT: u64 = 2;
+/// `yield()`, lets pending interrupts be handled.
+pub const SYS_YIELD: u64 = 3;
+/// `sleep(milliseconds)`.
+pub const SYS_SLEEP: u64 = 4;
+/// `mmap(len)`, maps `len` bytes of zeroed memory and returns its address.
+pub const SYS_MMAP: u64 = 5;
+
+/// Why a system call failed, returned negated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// There is no system call with that number.
+    NoSuchCall = 1,
+    /// There is no file with that descriptor.
+    BadFile = 2,
+    /// A pointer isn't to mapped user memory.
+    BadAddress = 3,
+    InvalidArgument = 4,
+    OutOfMemory = 5,
+}
+
+type Syscall = fn(&SyscallFrame) -> Result<u64, SyscallError>;
+
+/// Handlers, indexed by system call number.
+static SYSCALLS: [Syscall; 6] = [
+    sys_write, sys_read, sys_exit, sys_yield, sys_sleep, sys_mmap,
+];
+
+/// The user's registers as the entry stub saved them, in the reverse order
+/// it pushes them.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// The system call number.
+    pub rax: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// The arguments in ABI order.
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+// Scratch space for the entry stub. There is one CPU so one of each does.
+// The user's stack pointer while the stub switches stacks.
+static mut USER_RSP: u64 = 0;
+// The kernel stack to switch to, the same as the TSS's RSP0.
+static mut KERNEL_RSP: u64 = 0;
+
+/// Point `syscall` at the entry stub. Call after `gdt::init`.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT segments are in the wrong order for sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // Run the stub with interrupts off until it is on the kernel stack, and
+    // don't let user flags leak into the kernel
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Set the stack the entry stub switches to, see `gdt::set_kernel_stack`.
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    unsafe { KERNEL_RSP = top.as_u64() };
+}
+
+/// Called by the entry stub with the frame it built on the kernel stack.
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &SyscallFrame) -> i64 {
+    let result = match SYSCALLS.get(frame.rax as usize) {
+        Some(syscall) => syscall(frame),
+        None => Err(SyscallError::NoSuchCall),
+    };
+    // The stub is about to put the user's stack back
+    interrupts::disable();
+    match result {
+        Ok(value) => value as i64,
+        Err(err) => -(err as i64),
+    }
+}
+
+fn sys_write(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    let [fd, buf, len, ..] = frame.args();
+    let bytes = user_slice(buf, len)?;
+    let text = core::str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    match fd {
+        1 => crate::console::_print(format_args!("{}", text)),
+        2 => {
+            serial_print!("{}", text);
+        }
+        _ => return Err(SyscallError::BadFile),
+    }
+    Ok(len)
+}
+
+fn sys_read(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    let [fd, buf, len, ..] = frame.args();
+    if fd != 0 {
+        return Err(SyscallError::BadFile);
+    }
+    let bytes = user_slice_mut(buf, len)?;
+    let mut port = serial::port(ComPort::Com1).lock();
+    let mut count = 0;
+    for byte in bytes {
+        let Some(received) = port.try_receive() else {
+            break;
+        };
+        *byte = received;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn sys_exit(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    user::exit(frame.rdi as i64)
+}
+
+/// Only lets pending interrupts run. The program's thread can't be switched
+/// away from while it runs, see `thread`, so no other thread gets a turn.
+fn sys_yield(_frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    interrupts::enable();
+    core::hint::spin_loop();
+    interrupts::disable();
+    Ok(0)
+}
+
+fn sys_sleep(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    let deadline = pit::uptime() + Duration::from_millis(frame.rdi);
+    while pit::uptime() < deadline {
+        interrupts::enable_and_hlt();
+    }
+    interrupts::disable();
+    Ok(0)
+}
+
+fn sys_mmap(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    let len = frame.rdi;
+    if len == 0 || len > USER_END - USER_START {
+        return Err(SyscallError::InvalidArgument);
+    }
+    user::map(len)
+        .map(|addr| addr.as_u64())
+        .ok_or(SyscallError::OutOfMemory)
+}
+
+/// The `len` bytes at `ptr` if they are all mapped user memory.
+fn user_slice<'a>(ptr: u64, len: u64) -> Result<&'a [u8], SyscallError> {
+    check_user_range(ptr, len, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+/// Like `user_slice` but the memory must be writable too.
+fn user_slice_mut<'a>(ptr: u64, len: u64) -> Result<&'a mut [u8], SyscallError> {
+    check_user_range(ptr, len, true)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
+
+fn check_user_range(ptr: u64, len: u64, write: bool) -> Result<(), SyscallError> {
+    let end = ptr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    if ptr < USER_START || end > USER_END {
+        return Err(SyscallError::BadAddress);
+    }
+    if len == 0 || user::is_mapped(VirtAddr::new(ptr), len, write) {
+        Ok(())
+    } else {
+        Err(SyscallError::BadAddress)
+    }
+}
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// Switches to the kernel stack, saves the registers the ABI promises to
+// keep, calls `syscall_dispatch` and goes back with `sysret`. The kernel
+// stack is 16 byte aligned and the 10 pushes keep it that way at the `call`.
+// `syscall_dispatch` returns with interrupts off so nothing can land on the
+// user's stack in ring 0 once it is put back.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + {user_rsp}], rsp",
+    "mov rsp, [rip + {kernel_rsp}]",
+    "push qword ptr [rip + {user_rsp}]",
+    "push rcx",
+    "push r11",
+    "push rax",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r10",
+    "push r8",
+    "push r9",
+    "mov rdi, rsp",
+    "cld",
+    "call syscall_dispatch",
+    "pop r9",
+    "pop r8",
+    "pop r10",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    // The number, RAX has the result now
+    "add rsp, 8",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "sysretq",
+    user_rsp = sym USER_RSP,
+    kernel_rsp = sym KERNEL_RSP,
+);
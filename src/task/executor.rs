@@ -1,74 +1,216 @@
 #![allow(clippy::new_without_default)]
-use super::{Task, TaskId};
+use super::join::{self, JoinHandle, Joinable};
+use super::{Priority, Task, TaskId};
+use crate::allocator::AllocError;
+use crate::pit;
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::sync::{Arc, Weak};
 use alloc::task::Wake;
-use alloc::{collections::BTreeMap, sync::Arc};
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::cell::RefCell;
+use core::future::Future;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
 use core::task::Waker;
 use core::task::{Context, Poll};
-use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// `TaskWaker::last_wake` before the first wake.
+const NEVER: u64 = u64::MAX;
+
+/// How a task has been running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskStats {
+    pub priority: Priority,
+    /// How many times it has been polled.
+    pub polls: u64,
+    /// Time stamp counter cycles spent polling it.
+    pub poll_cycles: u64,
+    /// The `pit::ticks()` it was last woken at, if it has been.
+    pub last_wake: Option<u64>,
+}
+
+/// A task that hasn't finished and the waker that queues it.
+struct Slot {
+    task: Task,
+    waker: Arc<TaskWaker>,
+}
 
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    /// The tasks, at their waker's `TaskWaker::slot`. A `Vec` rather than a
+    /// map so spawning can make room first and fail instead of aborting.
+    slots: Vec<Option<Slot>>,
+    /// Slots of tasks that have finished, to be reused. Has room for every
+    /// slot so freeing one doesn't allocate.
+    free: Vec<usize>,
+    run_queue: Arc<RunQueue>,
+    /// Spawned through a `Spawner`, waiting to be taken in.
+    spawned: Rc<RefCell<VecDeque<Task>>>,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            run_queue: Arc::new(RunQueue::new()),
+            spawned: Rc::new(RefCell::new(VecDeque::new())),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with same ID already in tasks");
+    /// A handle for spawning tasks onto this executor from its tasks.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawned: self.spawned.clone(),
         }
-        self.task_queue.push(task_id).expect("task queue full");
+    }
+
+    pub fn spawn(&mut self, task: Task) -> TaskId {
+        self.insert(task)
+            .unwrap_or_else(|err| alloc::alloc::handle_alloc_error(err.layout))
+    }
+
+    /// Spawn a task running `future`, or return an error if the heap has no
+    /// room for it so the caller can do without.
+    pub fn try_spawn(
+        &mut self,
+        future: impl Future<Output = ()> + 'static,
+    ) -> Result<TaskId, AllocError> {
+        self.insert(Task::try_new(future)?)
+    }
+
+    /// Give `task` a slot and a waker and queue it. Everything that can fail
+    /// is allocated before anything is changed.
+    fn insert(&mut self, task: Task) -> Result<TaskId, AllocError> {
+        let (task_id, priority) = (task.id, task.priority);
+        if self.free.is_empty() {
+            let slots = self.slots.len() + 1;
+            self.slots.try_reserve(1).map_err(|_| AllocError {
+                layout: Layout::new::<Option<Slot>>(),
+            })?;
+            self.free.try_reserve(slots).map_err(|_| AllocError {
+                layout: Layout::array::<usize>(slots).unwrap_or(Layout::new::<usize>()),
+            })?;
+        }
+        let index = self.free.last().copied().unwrap_or(self.slots.len());
+        let run_queue = Arc::downgrade(&self.run_queue);
+        let waker =
+            Arc::try_new(TaskWaker::new(task_id, index, priority, run_queue)).map_err(|_| {
+                AllocError {
+                    layout: Layout::new::<TaskWaker>(),
+                }
+            })?;
+        if self.free.pop().is_none() {
+            self.slots.push(None);
+        }
+        self.run_queue.push(&waker);
+        self.slots[index] = Some(Slot { task, waker });
+        Ok(task_id)
+    }
+
+    /// The task with id `task_id`, if it hasn't finished.
+    fn slot(&self, task_id: TaskId) -> Option<&Slot> {
+        self.slots
+            .iter()
+            .flatten()
+            .find(|slot| slot.task.id == task_id)
+    }
+
+    /// How the task is doing, if it hasn't finished.
+    pub fn stats(&self, task_id: TaskId) -> Option<TaskStats> {
+        let slot = self.slot(task_id)?;
+        let last_wake =
+            Some(slot.waker.last_wake.load(Ordering::Relaxed)).filter(|&tick| tick != NEVER);
+        Some(TaskStats {
+            priority: slot.task.priority,
+            polls: slot.task.polls,
+            poll_cycles: slot.task.poll_cycles,
+            last_wake,
+        })
+    }
+
+    /// The tasks that haven't finished.
+    pub fn tasks(&self) -> impl Iterator<Item = TaskId> + '_ {
+        self.slots.iter().flatten().map(|slot| slot.task.id)
     }
 
     pub fn run(&mut self) -> ! {
         loop {
+            super::timer::wake_expired();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
     }
 
+    /// Poll tasks until none are ready, without waiting for any that are
+    /// waiting on something else.
+    pub fn run_until_idle(&mut self) {
+        loop {
+            super::timer::wake_expired();
+            if self.run_queue.is_empty() && self.spawned.borrow().is_empty() {
+                break;
+            }
+            self.run_ready_tasks();
+        }
+    }
+
+    /// One round of polling, highest priority first. Each priority gets its
+    /// budget of polls, after which the rest of its tasks wait for the next
+    /// round so a task that keeps waking itself can't starve the others.
     fn run_ready_tasks(&mut self) {
+        self.take_spawned();
         // destructure `self` to avoid borrow checker errors
         let Self {
-            tasks,
-            task_queue,
-            waker_cache,
+            slots,
+            free,
+            run_queue,
+            ..
         } = self;
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new_waker(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+        for priority in Priority::ALL {
+            for _ in 0..priority.budget() {
+                let Some(task_waker) = run_queue.pop(priority) else {
+                    break;
+                };
+                let index = task_waker.slot;
+                // The task may be gone, and its slot taken by another
+                let task = match slots.get_mut(index) {
+                    Some(Some(slot)) if slot.task.id == task_waker.task_id => &mut slot.task,
+                    _ => continue,
+                };
+                let waker = Waker::from(task_waker);
+                let mut context = Context::from_waker(&waker);
+                match task.poll(&mut context) {
+                    Poll::Ready(()) => {
+                        // task done -> free its slot, dropping its waker
+                        slots[index] = None;
+                        free.push(index);
+                    }
+                    Poll::Pending => {}
                 }
-                Poll::Pending => {}
             }
         }
     }
 
+    /// Queue the tasks spawners have spawned since last time.
+    fn take_spawned(&mut self) {
+        loop {
+            // Not borrowed while spawning, which may drop a task
+            let Some(task) = self.spawned.borrow_mut().pop_front() else {
+                break;
+            };
+            self.spawn(task);
+        }
+    }
+
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.run_queue.is_empty() && self.spawned.borrow().is_empty() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -76,21 +218,164 @@ impl Executor {
     }
 }
 
+/// Spawns tasks onto an `Executor` from inside its tasks, which can't borrow
+/// the executor while it is polling them.
+#[derive(Clone)]
+pub struct Spawner {
+    spawned: Rc<RefCell<VecDeque<Task>>>,
+}
+
+impl Spawner {
+    /// Spawn a task running `future` and return a handle to its output.
+    pub fn spawn<T: 'static>(&self, future: impl Future<Output = T> + 'static) -> JoinHandle<T> {
+        self.spawn_with_priority(future, Priority::Normal)
+    }
+
+    pub fn spawn_with_priority<T: 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static,
+        priority: Priority,
+    ) -> JoinHandle<T> {
+        let shared = join::shared();
+        let task = Task::new(Joinable::new(future, shared.clone())).with_priority(priority);
+        self.push(task, shared)
+    }
+
+    /// Like `spawn` but returns an error if the heap has no room for
+    /// `future`.
+    pub fn try_spawn<T: 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static,
+    ) -> Result<JoinHandle<T>, AllocError> {
+        let shared = join::shared();
+        let task = Task::try_new(Joinable::new(future, shared.clone()))?;
+        Ok(self.push(task, shared))
+    }
+
+    fn push<T>(&self, task: Task, shared: join::SharedState<T>) -> JoinHandle<T> {
+        let handle = JoinHandle::new(task.id, shared);
+        self.spawned.borrow_mut().push_back(task);
+        handle
+    }
+}
+
+/// Tasks waiting to be polled, a list for each priority linked through the
+/// tasks' wakers. A task is on a list at most once, see
+/// `TaskWaker::scheduled`, so the lists can't fill up and pushing doesn't
+/// allocate. Wakers push from interrupt handlers too so the lists are only
+/// locked with interrupts off.
+struct RunQueue {
+    lists: Mutex<[WakerList; Priority::ALL.len()]>,
+}
+
+/// Each waker on the list holds a reference to itself, see `Arc::into_raw`.
+struct WakerList {
+    head: *mut TaskWaker,
+    tail: *mut TaskWaker,
+}
+
+// The wakers are only touched with the lock held
+unsafe impl Send for WakerList {}
+
+impl RunQueue {
+    fn new() -> Self {
+        RunQueue {
+            lists: Mutex::new(core::array::from_fn(|_| WakerList {
+                head: ptr::null_mut(),
+                tail: ptr::null_mut(),
+            })),
+        }
+    }
+
+    /// Queue the task unless it already is.
+    /// WARNING Called by interrupt handlers, must not block or allocate.
+    fn push(&self, task_waker: &Arc<TaskWaker>) {
+        if task_waker.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let node = Arc::into_raw(task_waker.clone()).cast_mut();
+        interrupts::without_interrupts(|| {
+            let mut lists = self.lists.lock();
+            let list = &mut lists[task_waker.priority.index()];
+            if list.tail.is_null() {
+                list.head = node;
+            } else {
+                unsafe { (*list.tail).next.store(node, Ordering::Relaxed) };
+            }
+            list.tail = node;
+        });
+    }
+
+    fn pop(&self, priority: Priority) -> Option<Arc<TaskWaker>> {
+        let node = interrupts::without_interrupts(|| {
+            let mut lists = self.lists.lock();
+            let list = &mut lists[priority.index()];
+            if list.head.is_null() {
+                return None;
+            }
+            let node = list.head;
+            list.head = unsafe { (*node).next.swap(ptr::null_mut(), Ordering::Relaxed) };
+            if list.head.is_null() {
+                list.tail = ptr::null_mut();
+            }
+            Some(node)
+        })?;
+        let task_waker = unsafe { Arc::from_raw(node) };
+        // Waking it from now on queues it again
+        task_waker.scheduled.store(false, Ordering::Release);
+        Some(task_waker)
+    }
+
+    fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            let lists = self.lists.lock();
+            lists.iter().all(|list| list.head.is_null())
+        })
+    }
+}
+
+impl Drop for RunQueue {
+    fn drop(&mut self) {
+        for priority in Priority::ALL {
+            while self.pop(priority).is_some() {}
+        }
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// Where the task is in `Executor::slots`.
+    slot: usize,
+    priority: Priority,
+    /// Weak so the wakers queued on it don't keep it alive.
+    run_queue: Weak<RunQueue>,
+    /// The `pit::ticks()` of the last wake, or NEVER.
+    last_wake: AtomicU64,
+    /// Whether it is on the run queue, so waking it again does nothing.
+    scheduled: AtomicBool,
+    /// The next waker on its run queue list.
+    next: AtomicPtr<TaskWaker>,
 }
 
 impl TaskWaker {
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+    fn new(task_id: TaskId, slot: usize, priority: Priority, run_queue: Weak<RunQueue>) -> Self {
+        TaskWaker {
+            task_id,
+            slot,
+            priority,
+            run_queue,
+            last_wake: AtomicU64::new(NEVER),
+            scheduled: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
     }
 
-    fn new_waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+    fn wake_task(self: &Arc<Self>) {
+        self.last_wake.store(pit::ticks(), Ordering::Relaxed);
+        // Gone with its executor
+        if let Some(run_queue) = self.run_queue.upgrade() {
+            run_queue.push(self);
+        }
     }
 }
 
@@ -103,3 +388,136 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+#[test_case]
+fn test_higher_priorities_run_first() {
+    use core::sync::atomic::AtomicUsize;
+
+    static ORDER: AtomicUsize = AtomicUsize::new(0);
+    static RAN: [AtomicUsize; 3] = [
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+    ];
+
+    let mut executor = Executor::new();
+    for priority in Priority::ALL.into_iter().rev() {
+        let task = Task::new(async move {
+            let order = ORDER.fetch_add(1, Ordering::Relaxed) + 1;
+            RAN[priority.index()].store(order, Ordering::Relaxed);
+        });
+        executor.spawn(task.with_priority(priority));
+    }
+    executor.run_ready_tasks();
+    let ran = RAN.each_ref().map(|ran| ran.load(Ordering::Relaxed));
+    assert_eq!(ran, [1, 2, 3]);
+}
+
+#[test_case]
+fn test_poll_budget() {
+    use core::sync::atomic::AtomicBool;
+
+    static LOW_RAN: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    let busy = Task::new(async {
+        loop {
+            super::yield_now().await;
+        }
+    });
+    let busy = executor.spawn(busy.with_priority(Priority::High));
+    let low = Task::new(async { LOW_RAN.store(true, Ordering::Relaxed) });
+    executor.spawn(low.with_priority(Priority::Low));
+    executor.run_ready_tasks();
+    assert!(LOW_RAN.load(Ordering::Relaxed));
+    let stats = executor.stats(busy).unwrap();
+    assert_eq!(stats.polls, Priority::High.budget() as u64);
+    assert!(stats.last_wake.is_some());
+}
+
+#[test_case]
+fn test_join_task_spawned_by_task() {
+    use core::sync::atomic::AtomicU64;
+
+    static RESULT: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let outer = executor.spawner().spawn(async move {
+        let inner = spawner.spawn(async {
+            super::yield_now().await;
+            40
+        });
+        let value = inner.await.unwrap() + 2;
+        RESULT.store(value, Ordering::Relaxed);
+        value
+    });
+    while !outer.is_finished() {
+        executor.run_ready_tasks();
+    }
+    assert_eq!(RESULT.load(Ordering::Relaxed), 42);
+    assert!(executor.tasks().next().is_none());
+}
+
+#[test_case]
+fn test_abort() {
+    use core::sync::atomic::AtomicBool;
+
+    static ABORTED: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let forever = spawner.spawn(async {
+        loop {
+            super::yield_now().await;
+        }
+    });
+    let task_id = forever.id();
+    executor.run_ready_tasks();
+    assert!(executor.stats(task_id).is_some());
+    forever.abort();
+    executor.run_ready_tasks();
+    assert!(forever.is_finished());
+    assert!(executor.stats(task_id).is_none());
+    spawner.spawn(async move {
+        let aborted = forever.await == Err(join::JoinError::Aborted);
+        ABORTED.store(aborted, Ordering::Relaxed);
+    });
+    executor.run_ready_tasks();
+    assert!(ABORTED.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn test_waking_twice_polls_once() {
+    use core::sync::atomic::AtomicUsize;
+
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    let mut woken = false;
+    executor.spawn(Task::new(core::future::poll_fn(move |cx| {
+        POLLS.fetch_add(1, Ordering::Relaxed);
+        if woken {
+            return Poll::Ready(());
+        }
+        woken = true;
+        for _ in 0..5 {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    })));
+    executor.run_until_idle();
+    assert_eq!(POLLS.load(Ordering::Relaxed), 2);
+}
+
+#[test_case]
+fn test_finished_slots_are_reused() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {}));
+    executor.run_ready_tasks();
+    let task_id = executor.try_spawn(async {}).unwrap();
+    assert_eq!(executor.slots.len(), 1);
+    assert!(executor.stats(task_id).is_some());
+    executor.run_ready_tasks();
+    assert!(executor.tasks().next().is_none());
+}
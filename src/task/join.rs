@@ -0,0 +1,145 @@
+// Joining Tasks
+// A task spawned through a `Spawner` runs its future wrapped in a `Joinable`,
+// which hands the output to the task's `JoinHandle` and wakes whoever is
+// waiting on it. Aborting sets a flag and wakes the task, and the next time
+// it is polled the `Joinable` finishes without polling the future again, so
+// the executor drops it.
+// There is no unwinding, a panic in a task stops the kernel. The executor
+// does keep track of which task it is polling, see `super::current`, so the
+// panic handler can say which one it was.
+use super::TaskId;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+/// Why a task didn't finish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// `JoinHandle::abort` was called first.
+    Aborted,
+}
+
+/// What the task and its handle share.
+pub(super) struct Shared<T> {
+    /// Set when the task finishes, until the handle takes it.
+    result: Option<Result<T, JoinError>>,
+    finished: bool,
+    aborted: bool,
+    /// Waiting for the task to finish.
+    join_waker: Option<Waker>,
+    /// The task's own waker, to wake it up to abort.
+    task_waker: Option<Waker>,
+}
+
+pub(super) type SharedState<T> = Rc<RefCell<Shared<T>>>;
+
+pub(super) fn shared<T>() -> SharedState<T> {
+    Rc::new(RefCell::new(Shared {
+        result: None,
+        finished: false,
+        aborted: false,
+        join_waker: None,
+        task_waker: None,
+    }))
+}
+
+/// A future that completes with the output of a spawned task. Dropping it
+/// leaves the task running.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    shared: SharedState<T>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(id: TaskId, shared: SharedState<T>) -> Self {
+        JoinHandle { id, shared }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Stop the task the next time it would be polled. Does nothing if it
+    /// has already finished.
+    pub fn abort(&self) {
+        let mut shared = self.shared.borrow_mut();
+        if shared.finished {
+            return;
+        }
+        shared.aborted = true;
+        // Not polled yet means it is still queued from being spawned
+        if let Some(waker) = shared.task_waker.take() {
+            drop(shared);
+            waker.wake();
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.shared.borrow().finished
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.borrow_mut();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None if shared.finished => panic!("JoinHandle polled after completion"),
+            None => {
+                shared.join_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Runs `future` and hands its output to the `JoinHandle`.
+pub(super) struct Joinable<F: Future> {
+    future: F,
+    shared: SharedState<F::Output>,
+}
+
+impl<F: Future> Joinable<F> {
+    pub(super) fn new(future: F, shared: SharedState<F::Output>) -> Self {
+        Joinable { future, shared }
+    }
+
+    fn finish(&self, result: Result<F::Output, JoinError>) {
+        let mut shared = self.shared.borrow_mut();
+        shared.result = Some(result);
+        shared.finished = true;
+        shared.task_waker = None;
+        if let Some(waker) = shared.join_waker.take() {
+            drop(shared);
+            waker.wake();
+        }
+    }
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // `future` is never moved out of `self`
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.shared.borrow().aborted {
+            this.finish(Err(JoinError::Aborted));
+            return Poll::Ready(());
+        }
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                this.finish(Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => {
+                this.shared.borrow_mut().task_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -1,11 +1,13 @@
 #![allow(clippy::new_without_default)]
-use crate::println;
+use crate::allocator::{try_vec_with_capacity, AllocError};
+use crate::warn;
+use alloc::boxed::Box;
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
     task::{Context, Poll},
 };
-use crossbeam_queue::ArrayQueue;
 use futures_util::stream::Stream;
 use futures_util::task::AtomicWaker;
 
@@ -15,7 +17,7 @@ use futures_util::task::AtomicWaker;
 static WAKER: AtomicWaker = AtomicWaker::new();
 
 /// A static queue for holding keyboard scancodes
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static SCANCODE_QUEUE: OnceCell<ScancodeQueue> = OnceCell::uninit();
 /// The max number of keyboard scancodes we can have in the queue
 const SCANCODE_QUEUE_LENGTH: usize = 100;
 
@@ -26,10 +28,62 @@ pub struct ScancodeStream {
 
 impl ScancodeStream {
     pub fn new() -> Self {
+        Self::try_new().expect("no memory for the scancode queue")
+    }
+
+    /// Like `new` but returns an error if there is no room on the heap for
+    /// the queue, in which case keyboard input is dropped.
+    pub fn try_new() -> Result<Self, AllocError> {
+        let queue = ScancodeQueue::try_new(SCANCODE_QUEUE_LENGTH)?;
         SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_LENGTH))
+            .try_init_once(|| queue)
             .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        Ok(ScancodeStream { _private: () })
+    }
+}
+
+/// Scancodes waiting to be read. Only the interrupt handler pushes and only
+/// the stream pops so a ring buffer with atomic indices is all it takes.
+/// The indices only ever count up, wrapping, so head == tail is empty and
+/// tail - head == capacity is full.
+struct ScancodeQueue {
+    buffer: Box<[AtomicU8]>,
+    /// Where the next scancode is read from.
+    head: AtomicUsize,
+    /// Where the next scancode is written to.
+    tail: AtomicUsize,
+}
+
+impl ScancodeQueue {
+    fn try_new(capacity: usize) -> Result<Self, AllocError> {
+        let mut buffer = try_vec_with_capacity(capacity)?;
+        // Fits in the capacity so this won't allocate again
+        buffer.extend((0..capacity).map(|_| AtomicU8::new(0)));
+        Ok(ScancodeQueue {
+            buffer: buffer.into_boxed_slice(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        })
+    }
+
+    fn push(&self, scancode: u8) -> Result<(), u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == self.buffer.len() {
+            return Err(scancode);
+        }
+        self.buffer[tail % self.buffer.len()].store(scancode, Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let scancode = self.buffer[head % self.buffer.len()].load(Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(scancode)
     }
 }
 
@@ -44,33 +98,54 @@ impl Stream for ScancodeStream {
             .expect("SCANCODE_QUEUE not initialized");
 
         // fast path
-        if let Ok(scancode) = queue.pop() {
+        if let Some(scancode) = queue.pop() {
             return Poll::Ready(Some(scancode));
         }
 
         WAKER.register(cx.waker());
 
         match queue.pop() {
-            Ok(scancode) => {
+            Some(scancode) => {
                 WAKER.take();
                 Poll::Ready(Some(scancode))
             }
-            Err(crossbeam_queue::PopError) => Poll::Pending,
+            None => Poll::Pending,
         }
     }
 }
 
+/// The PS/2 keyboard controller raises IRQ1 when a scancode is ready.
+const IRQ: u8 = 1;
+/// The PS/2 controller's data port, where the scancode is read from.
+const DATA_PORT: u16 = 0x60;
+
+/// Start receiving scancodes from the keyboard interrupt.
+pub fn init() {
+    crate::interrupts::register_irq(IRQ, keyboard_interrupt_handler)
+        .expect("keyboard IRQ already registered");
+}
+
+/// WARNING Runs inside an interrupt handler.
+/// WARNING Must not block or allocate.
+fn keyboard_interrupt_handler() {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(DATA_PORT);
+    let scancode: u8 = unsafe { port.read() };
+    add_scancode(scancode);
+}
+
 /// WARNING Called by the keyboard interrupt handler.
 /// WARNING Must not block or allocate.
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if queue.push(scancode).is_err() {
-            println!("WARNING: scancode queue full; dropping keyboard input");
+            warn!("scancode queue full; dropping keyboard input");
         } else {
             WAKER.wake();
         }
     } else {
-        println!("WARNING: scancode queue uninitialized");
+        warn!("scancode queue uninitialized");
     }
 }
 
@@ -96,3 +171,16 @@ pub(crate) fn add_scancode(scancode: u8) {
 //             }
 //         }
 //     }
+
+#[test_case]
+fn test_scancode_queue_wraps() {
+    let queue = ScancodeQueue::try_new(2).unwrap();
+    assert_eq!(queue.push(1), Ok(()));
+    assert_eq!(queue.push(2), Ok(()));
+    assert_eq!(queue.push(3), Err(3));
+    assert_eq!(queue.pop(), Some(1));
+    assert_eq!(queue.push(3), Ok(()));
+    assert_eq!(queue.pop(), Some(2));
+    assert_eq!(queue.pop(), Some(3));
+    assert_eq!(queue.pop(), None);
+}
@@ -1,13 +1,18 @@
+use crate::allocator::{try_box, AllocError};
 use alloc::boxed::Box;
+use core::arch::x86_64::_rdtsc;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
 
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub mod timer;
 
+/// Identifies a task, never reused.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
@@ -15,19 +20,125 @@ impl TaskId {
     }
 }
 
+/// The task being polled, or NO_TASK.
+static CURRENT: AtomicU64 = AtomicU64::new(NO_TASK);
+const NO_TASK: u64 = u64::MAX;
+
+/// The task being polled, if there is one. Lets the panic handler tell which
+/// task panicked.
+pub fn current() -> Option<TaskId> {
+    match CURRENT.load(Ordering::Relaxed) {
+        NO_TASK => None,
+        id => Some(TaskId(id)),
+    }
+}
+
+/// Which tasks the `Executor` polls first. Each gets a budget of polls per
+/// round so the lower ones still get a turn, see `Priority::budget`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Input and anything else someone is waiting on.
+    High,
+    Normal,
+    /// Background work, like rendering.
+    Low,
+}
+
+impl Priority {
+    /// From highest to lowest.
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    /// How many tasks of this priority are polled per round, at most.
+    pub fn budget(self) -> usize {
+        match self {
+            Priority::High => 8,
+            Priority::Normal => 4,
+            Priority::Low => 2,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
 pub struct Task {
     id: TaskId,
+    priority: Priority,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    /// How many times it has been polled.
+    polls: u64,
+    /// Time stamp counter cycles spent polling it.
+    poll_cycles: u64,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            priority: Priority::Normal,
             future: Box::pin(future),
+            polls: 0,
+            poll_cycles: 0,
         }
     }
+
+    /// Like `new` but returns an error if the heap has no room for `future`.
+    pub fn try_new(future: impl Future<Output = ()> + 'static) -> Result<Task, AllocError> {
+        let future: Box<dyn Future<Output = ()>> = try_box(future)?;
+        Ok(Task {
+            id: TaskId::new(),
+            priority: Priority::Normal,
+            future: Box::into_pin(future),
+            polls: 0,
+            poll_cycles: 0,
+        })
+    }
+
+    /// Run at `priority` instead of `Priority::Normal`.
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        CURRENT.store(self.id.0, Ordering::Relaxed);
+        let start = unsafe { _rdtsc() };
+        let poll = self.future.as_mut().poll(context);
+        CURRENT.store(NO_TASK, Ordering::Relaxed);
+        self.polls += 1;
+        self.poll_cycles += unsafe { _rdtsc() }.wrapping_sub(start);
+        poll
+    }
+}
+
+/// A future that lets the other tasks run before it completes.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        // Back of the queue
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
     }
 }
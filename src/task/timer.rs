@@ -0,0 +1,66 @@
+use crate::pit;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use core::{future::Future, pin::Pin};
+use spin::Mutex;
+
+/// Wakers of sleeping tasks keyed by the tick they should wake on.
+/// The second half of the key keeps sleepers with the same deadline apart.
+/// Only touched from task context, never from the timer interrupt handler, so
+/// the interrupt handler doesn't need to lock or free anything.
+static SLEEPERS: Mutex<BTreeMap<(u64, u64), Waker>> = Mutex::new(BTreeMap::new());
+
+/// A future that completes once `duration` has passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(pit::ticks() + pit::duration_to_ticks(duration))
+}
+
+/// A future that completes once `pit::ticks()` reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+    id: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if pit::ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+        SLEEPERS
+            .lock()
+            .insert((self.deadline, self.id), cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        SLEEPERS.lock().remove(&(self.deadline, self.id));
+    }
+}
+
+/// Wake every task whose deadline has passed.
+/// Called by the `Executor` each time it wakes up, which happens at least once
+/// per timer interrupt.
+pub(crate) fn wake_expired() {
+    let now = pit::ticks();
+    let mut sleepers = SLEEPERS.lock();
+    while let Some(entry) = sleepers.first_entry() {
+        if entry.key().0 > now {
+            break;
+        }
+        entry.remove().wake();
+    }
+}
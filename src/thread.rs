@@ -0,0 +1,376 @@
+// Kernel Threads
+// Each thread has a stack of its own, with a guard page below it, and runs
+// until the timer takes the CPU away from it. Every TIME_SLICE ticks the
+// timer interrupt asks for a reschedule and on its way out, once the end of
+// interrupt has been sent, the running thread is switched for the one at
+// the front of the ready queue and goes to the back of it. Round robin.
+//   see: https://wiki.osdev.org/Scheduling_Algorithms
+// Switching saves the callee-saved registers on the old thread's stack and
+// its stack pointer in its `Thread`, then does the reverse for the new one.
+// Everything else was already saved by whoever called the switch, the
+// interrupt handler or `yield_now`. A new thread's stack is made to look
+// like it was switched away from right before `thread_entry`.
+//   see: https://wiki.osdev.org/Context_Switching
+// The code `init` runs on, and the async executor with it, is the boot
+// thread. Switching happens from interrupt handlers so it mustn't allocate:
+// queued threads are linked through the threads themselves. Threads that
+// have exited are only freed later, off their own stack, by `reap`.
+// Threads running user programs aren't preempted, there is only one set of
+// user state, see `user`.
+use crate::allocator::AllocError;
+use crate::stack::{KernelStack, DEFAULT_STACK_SIZE};
+use crate::vma::VmaError;
+use alloc::boxed::Box;
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// How many timer ticks a thread runs before the next one gets a turn.
+pub const TIME_SLICE: u64 = 10;
+
+/// Identifies a thread, never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+#[derive(Debug)]
+pub enum SpawnError {
+    /// No room on the heap for the thread.
+    Alloc(AllocError),
+    /// No room for its stack.
+    Stack(VmaError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    /// Exited, waiting to be reaped.
+    Dead,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    /// Where the stack pointer was left when it was switched away from.
+    rsp: u64,
+    state: State,
+    /// None for the boot thread, which runs on the bootloader's stack.
+    /// Only kept to be freed with the thread.
+    #[allow(dead_code)]
+    stack: Option<KernelStack>,
+    /// What to run, taken when the thread starts.
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// The next thread in the queue it is on.
+    next: *mut Thread,
+}
+
+/// A queue of threads linked through `Thread::next`.
+struct Queue {
+    head: *mut Thread,
+    tail: *mut Thread,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Queue {
+            head: ptr::null_mut(),
+            tail: ptr::null_mut(),
+        }
+    }
+
+    /// # Safety
+    ///    There is none. Must follow the rules.
+    unsafe fn push(&mut self, thread: *mut Thread) {
+        (*thread).next = ptr::null_mut();
+        if self.tail.is_null() {
+            self.head = thread;
+        } else {
+            (*self.tail).next = thread;
+        }
+        self.tail = thread;
+    }
+
+    fn pop(&mut self) -> Option<*mut Thread> {
+        if self.head.is_null() {
+            return None;
+        }
+        let thread = self.head;
+        unsafe {
+            self.head = (*thread).next;
+            (*thread).next = ptr::null_mut();
+        }
+        if self.head.is_null() {
+            self.tail = ptr::null_mut();
+        }
+        Some(thread)
+    }
+}
+
+struct Scheduler {
+    /// Null until the first thread is spawned.
+    current: *mut Thread,
+    ready: Queue,
+    dead: Queue,
+    threads: usize,
+}
+
+// The threads are only touched with the lock held
+unsafe impl Send for Scheduler {}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    current: ptr::null_mut(),
+    ready: Queue::new(),
+    dead: Queue::new(),
+    threads: 1,
+});
+/// The thread `init` runs on.
+static mut BOOT_THREAD: Thread = Thread {
+    id: ThreadId(0),
+    name: "boot",
+    rsp: 0,
+    state: State::Running,
+    stack: None,
+    entry: None,
+    next: ptr::null_mut(),
+};
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// Set by the timer when the running thread's time is up.
+static RESCHEDULE: AtomicBool = AtomicBool::new(false);
+static SLICE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Start running `f` on a thread of its own.
+pub fn spawn<F>(name: &'static str, f: F) -> Result<ThreadId, SpawnError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    reap();
+    let stack = KernelStack::new(DEFAULT_STACK_SIZE, name).map_err(SpawnError::Stack)?;
+    let entry: Box<dyn FnOnce() + Send> =
+        crate::allocator::try_box(f).map_err(SpawnError::Alloc)?;
+    let id = ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    // What `switch_context` pops, then the return address of `thread_entry`
+    let top = stack.top().as_u64();
+    let frame = [0, 0, 0, 0, 0, 0, thread_entry as *const () as u64, 0];
+    let rsp = top - (frame.len() * 8) as u64;
+    unsafe { ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len()) };
+    let thread = crate::allocator::try_box(Thread {
+        id,
+        name,
+        rsp,
+        state: State::Ready,
+        stack: Some(stack),
+        entry: Some(entry),
+        next: ptr::null_mut(),
+    })
+    .map_err(SpawnError::Alloc)?;
+    let thread = Box::into_raw(thread);
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.current.is_null() {
+            scheduler.current = ptr::addr_of_mut!(BOOT_THREAD);
+        }
+        scheduler.threads += 1;
+        unsafe { scheduler.ready.push(thread) };
+    });
+    Ok(id)
+}
+
+/// Give the rest of the time slice to the next thread.
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+    reap();
+}
+
+/// Yield until `duration` has passed.
+pub fn sleep(duration: Duration) {
+    let deadline = crate::pit::ticks() + crate::pit::duration_to_ticks(duration);
+    while crate::pit::ticks() < deadline {
+        yield_now();
+    }
+}
+
+/// End the calling thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let current = SCHEDULER.lock().current;
+    assert!(
+        current != ptr::addr_of_mut!(BOOT_THREAD),
+        "the boot thread can't exit"
+    );
+    unsafe { (*current).state = State::Dead };
+    schedule();
+    unreachable!("a dead thread was scheduled");
+}
+
+/// The thread that is running.
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| {
+        let current = SCHEDULER.lock().current;
+        if current.is_null() {
+            ThreadId(0)
+        } else {
+            unsafe { (*current).id }
+        }
+    })
+}
+
+/// The name of the thread that is running.
+pub fn current_name() -> &'static str {
+    interrupts::without_interrupts(|| {
+        let current = SCHEDULER.lock().current;
+        if current.is_null() {
+            "boot"
+        } else {
+            unsafe { (*current).name }
+        }
+    })
+}
+
+/// How many threads there are, counting the boot thread and threads that
+/// have exited but aren't reaped yet.
+pub fn count() -> usize {
+    interrupts::without_interrupts(|| SCHEDULER.lock().threads)
+}
+
+/// WARNING Called by the timer interrupt handler.
+/// WARNING Must not block or allocate.
+pub(crate) fn tick() {
+    if SLICE_TICKS.fetch_add(1, Ordering::Relaxed) + 1 >= TIME_SLICE {
+        SLICE_TICKS.store(0, Ordering::Relaxed);
+        RESCHEDULE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Switch threads if the time slice is up. Called at the end of every
+/// interrupt handler, after the end of interrupt has been sent.
+/// WARNING Must not block or allocate.
+pub(crate) fn preempt() {
+    if RESCHEDULE.swap(false, Ordering::Relaxed) && !crate::user::is_running() {
+        schedule();
+    }
+}
+
+/// Switch to the next ready thread, if there is one. The current one goes
+/// to the back of the queue, or to be reaped if it is dead.
+/// Must be called with interrupts off.
+fn schedule() {
+    let mut scheduler = match SCHEDULER.try_lock() {
+        Some(scheduler) => scheduler,
+        // Interrupted someone changing the queues, try again next tick
+        None => return,
+    };
+    let previous = scheduler.current;
+    if previous.is_null() {
+        return;
+    }
+    let Some(next) = scheduler.ready.pop() else {
+        return;
+    };
+    unsafe {
+        match (*previous).state {
+            State::Dead => scheduler.dead.push(previous),
+            _ => {
+                (*previous).state = State::Ready;
+                scheduler.ready.push(previous);
+            }
+        }
+        (*next).state = State::Running;
+        scheduler.current = next;
+        SLICE_TICKS.store(0, Ordering::Relaxed);
+        drop(scheduler);
+        switch_context(ptr::addr_of_mut!((*previous).rsp), (*next).rsp);
+    }
+}
+
+/// Free the threads that have exited.
+fn reap() {
+    loop {
+        let dead = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let dead = scheduler.dead.pop()?;
+            scheduler.threads -= 1;
+            Some(dead)
+        });
+        match dead {
+            Some(thread) => drop(unsafe { Box::from_raw(thread) }),
+            None => break,
+        }
+    }
+}
+
+/// Where new threads start, with interrupts off, straight out of
+/// `switch_context`.
+extern "C" fn thread_entry() -> ! {
+    let current = SCHEDULER.lock().current;
+    let entry = unsafe { (*current).entry.take() };
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+
+extern "C" {
+    /// Save the callee-saved registers and the stack pointer to `old_rsp`
+    /// and restore them from `new_rsp`.
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+#[test_case]
+fn test_threads_are_preempted() {
+    use core::sync::atomic::AtomicUsize;
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static COUNTERS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    let threads = count();
+    for (i, name) in ["spinner 0", "spinner 1"].into_iter().enumerate() {
+        spawn(name, move || {
+            // Never yields, only the timer gets anyone else a turn
+            while !STOP.load(Ordering::Relaxed) {
+                COUNTERS[i].fetch_add(1, Ordering::Relaxed);
+            }
+            FINISHED.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+    }
+    // So does this thread
+    while COUNTERS
+        .iter()
+        .any(|counter| counter.load(Ordering::Relaxed) == 0)
+    {
+        core::hint::spin_loop();
+    }
+    STOP.store(true, Ordering::Relaxed);
+    while FINISHED.load(Ordering::Relaxed) < 2 {
+        yield_now();
+    }
+    // Let them finish exiting and get reaped
+    while count() > threads {
+        yield_now();
+    }
+}
@@ -0,0 +1,311 @@
+// User Mode
+// Code we don't trust runs in ring 3, in an address space of its own where
+// only the pages it was given are user accessible. It can't touch the
+// kernel, the VGA buffer or the hardware, everything goes through the system
+// calls in `syscall`.
+//   see: https://wiki.osdev.org/Getting_to_Ring_3
+// Ring 3 is entered with `iretq` on a frame built to look like the program
+// was interrupted at its entry point. Before that `user_enter` saves the
+// kernel's callee-saved registers on the caller's stack, and when the
+// program exits, or is killed by an exception, `user_return` picks them up
+// again so it looks like `user_enter` returned the exit code. Meanwhile
+// interrupts and system calls from ring 3 run on a kernel stack of the
+// program's own.
+use crate::address_space::{self, AddressSpace, AddressSpaceError, USER_END, USER_START};
+use crate::exceptions::ExceptionFrame;
+use crate::stack::{KernelStack, DEFAULT_STACK_SIZE};
+use crate::vma::VmaError;
+use crate::{gdt, warn};
+use core::arch::global_asm;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Where `from_code` puts the code.
+pub const CODE_START: u64 = USER_START;
+/// The top of the user stack, with an unmapped page above it.
+pub const STACK_TOP: u64 = USER_END - Size4KiB::SIZE;
+pub const STACK_SIZE: u64 = 64 * 1024;
+/// Where `mmap` starts handing out memory.
+pub const MMAP_START: u64 = USER_START + 0x_0100_0000_0000;
+/// A program killed by an exception exits with this plus the vector.
+pub const KILLED_EXIT_CODE: i64 = 128;
+
+#[derive(Debug)]
+pub enum UserError {
+    AddressSpace(AddressSpaceError),
+    /// No room for the kernel stack.
+    Vma(VmaError),
+}
+
+impl From<AddressSpaceError> for UserError {
+    fn from(err: AddressSpaceError) -> Self {
+        UserError::AddressSpace(err)
+    }
+}
+
+/// A program's memory and where to start it.
+#[derive(Debug)]
+pub struct Program {
+    space: AddressSpace,
+    entry: VirtAddr,
+    stack_top: VirtAddr,
+    /// Where the next `mmap` goes.
+    mmap_next: VirtAddr,
+}
+
+impl Program {
+    /// An empty address space with a stack.
+    pub fn new() -> Result<Self, UserError> {
+        let mut space = AddressSpace::new()?;
+        let flags = user_flags(true);
+        space.map_zeroed(VirtAddr::new(STACK_TOP - STACK_SIZE), STACK_SIZE, flags)?;
+        Ok(Program {
+            space,
+            entry: VirtAddr::new(CODE_START),
+            stack_top: VirtAddr::new(STACK_TOP),
+            mmap_next: VirtAddr::new(MMAP_START),
+        })
+    }
+
+    /// A program that is just `code`, position independent machine code
+    /// that starts at its first byte.
+    pub fn from_code(code: &[u8]) -> Result<Self, UserError> {
+        let mut program = Self::new()?;
+        let start = VirtAddr::new(CODE_START);
+        program
+            .space
+            .map_zeroed(start, code.len() as u64, user_flags(false))?;
+        program.space.write(start, code)?;
+        Ok(program)
+    }
+
+    pub fn address_space(&mut self) -> &mut AddressSpace {
+        &mut self.space
+    }
+
+    pub fn set_entry(&mut self, entry: VirtAddr) {
+        self.entry = entry;
+    }
+
+    pub fn set_stack_top(&mut self, stack_top: VirtAddr) {
+        self.stack_top = stack_top;
+    }
+
+    /// Run the program in ring 3 until it exits and return its exit code.
+    /// Its memory is freed afterwards.
+    pub fn run(self) -> Result<i64, UserError> {
+        let kernel_stack =
+            KernelStack::new(DEFAULT_STACK_SIZE, "user kernel stack").map_err(UserError::Vma)?;
+        let (entry, stack_top) = (self.entry, self.stack_top);
+        let selectors = gdt::selectors();
+        let code = interrupts::without_interrupts(|| {
+            let mut current = CURRENT.lock();
+            assert!(current.is_none(), "a user program is already running");
+            unsafe { self.space.activate() };
+            *current = Some(self);
+            drop(current);
+            RUNNING.store(true, Ordering::Relaxed);
+            gdt::set_kernel_stack(kernel_stack.top());
+            unsafe {
+                user_enter(
+                    entry.as_u64(),
+                    stack_top.as_u64(),
+                    addr_of_mut!(RETURN_RSP),
+                    selectors.user_code_selector.0 as u64,
+                    selectors.user_data_selector.0 as u64,
+                )
+            }
+        });
+        let program = interrupts::without_interrupts(|| {
+            unsafe { address_space::switch_to_kernel() };
+            RUNNING.store(false, Ordering::Relaxed);
+            CURRENT.lock().take()
+        });
+        drop(program);
+        drop(kernel_stack);
+        Ok(code)
+    }
+}
+
+/// The program that is running, while it is.
+static CURRENT: Mutex<Option<Program>> = Mutex::new(None);
+/// The kernel stack pointer `user_enter` left its registers at.
+static mut RETURN_RSP: u64 = 0;
+/// Whether there is a `CURRENT` program, without taking the lock.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Whether a program is running. Its thread can't be switched away from
+/// until it exits, see `thread`.
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// End the running program with `code`, returning from its `Program::run`.
+/// Must be called on the program's kernel stack, from a system call or an
+/// exception in ring 3.
+pub fn exit(code: i64) -> ! {
+    interrupts::disable();
+    unsafe { user_return(RETURN_RSP, code) }
+}
+
+/// End the running program for causing an exception it can't recover from.
+pub fn kill(frame: &ExceptionFrame) -> ! {
+    warn!(
+        "user program killed by exception {} at {:#x}",
+        frame.vector, frame.rip
+    );
+    exit(KILLED_EXIT_CODE + frame.vector as i64)
+}
+
+/// Map `len` bytes of zeroed memory into the running program.
+pub fn map(len: u64) -> Option<VirtAddr> {
+    interrupts::without_interrupts(|| {
+        let mut current = CURRENT.lock();
+        let program = current.as_mut()?;
+        let start = program.mmap_next;
+        program
+            .space
+            .map_zeroed(start, len, user_flags(true))
+            .ok()?;
+        // Leave a page unmapped between mappings
+        program.mmap_next = (start + len).align_up(Size4KiB::SIZE) + Size4KiB::SIZE;
+        Some(start)
+    })
+}
+
+/// Whether the running program can access all `len` bytes at `start`, and
+/// write to them if `write` is set.
+pub fn is_mapped(start: VirtAddr, len: u64, write: bool) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut current = CURRENT.lock();
+        let Some(program) = current.as_mut() else {
+            return false;
+        };
+        program.space.with_mapper(|mapper| {
+            let mut addr = start.align_down(Size4KiB::SIZE);
+            while addr < start + len {
+                let TranslateResult::Mapped { flags, .. } = mapper.translate(addr) else {
+                    return false;
+                };
+                if !flags.contains(user_flags(write)) {
+                    return false;
+                }
+                addr += Size4KiB::SIZE;
+            }
+            true
+        })
+    })
+}
+
+fn user_flags(writable: bool) -> PageTableFlags {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if writable {
+        flags | PageTableFlags::WRITABLE
+    } else {
+        flags
+    }
+}
+
+extern "C" {
+    /// Enter ring 3 at `entry` with `stack`, leaving the kernel's registers
+    /// and where they are in `saved_rsp`. Returns the exit code.
+    fn user_enter(entry: u64, stack: u64, saved_rsp: *mut u64, code: u64, data: u64) -> i64;
+    /// Return `code` from the `user_enter` that left `saved_rsp`.
+    fn user_return(saved_rsp: u64, code: i64) -> !;
+}
+
+// The general purpose registers are cleared before `iretq` so nothing of the
+// kernel's leaks into the program.
+global_asm!(
+    ".global user_enter",
+    ".global user_return",
+    "user_enter:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "mov [rdx], rsp",
+    "push r8",
+    "push rsi",
+    "push 0x202",
+    "push rcx",
+    "push rdi",
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "iretq",
+    "user_return:",
+    "mov rsp, rdi",
+    "mov rax, rsi",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+#[test_case]
+fn test_mmap_and_exit() {
+    let code = [
+        0xbf, 0x00, 0x10, 0x00, 0x00, // mov edi, 4096
+        0xb8, 0x05, 0x00, 0x00, 0x00, // mov eax, SYS_MMAP
+        0x0f, 0x05, // syscall
+        0x48, 0xc7, 0x00, 0x07, 0x00, 0x00, 0x00, // mov qword [rax], 7
+        0x48, 0x8b, 0x38, // mov rdi, [rax]
+        0x48, 0x83, 0xc7, 0x23, // add rdi, 35
+        0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, SYS_EXIT
+        0x0f, 0x05, // syscall
+    ];
+    assert_eq!(Program::from_code(&code).unwrap().run().unwrap(), 42);
+}
+
+#[test_case]
+fn test_bad_pointer() {
+    let code = [
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+        0x31, 0xf6, // xor esi, esi
+        0xba, 0x05, 0x00, 0x00, 0x00, // mov edx, 5
+        0x31, 0xc0, // xor eax, eax (SYS_WRITE)
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, SYS_EXIT
+        0x0f, 0x05, // syscall
+    ];
+    let code = Program::from_code(&code).unwrap().run().unwrap();
+    assert_eq!(code, -(crate::syscall::SyscallError::BadAddress as i64));
+}
+
+#[test_case]
+fn test_kernel_memory_is_off_limits() {
+    let code = [
+        0xc6, 0x04, 0x25, 0x00, 0x80, 0x0b, 0x00, 0x41, // mov byte [0xb8000], 'A'
+        0xbf, 0x00, 0x00, 0x00, 0x00, // mov edi, 0
+        0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, SYS_EXIT
+        0x0f, 0x05, // syscall
+    ];
+    let code = Program::from_code(&code).unwrap().run().unwrap();
+    assert_eq!(code, KILLED_EXIT_CODE + 14);
+}
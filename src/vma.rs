@@ -0,0 +1,607 @@
+// Virtual Memory Areas
+// Keeps track of which ranges of the kernel's virtual address space are
+// spoken for so nothing gets mapped on top of something else. Fixed parts of
+// the layout are reserved at boot:
+//   physical memory   wherever the bootloader put it
+//   heap              HEAP_START, HEAP_RESERVED bytes
+//   APIC MMIO         APIC_MMIO_START
+//   boot              any other P4 entry the bootloader used, eg. the
+//                     kernel image and its stack
+// and everything else is handed out on request from the range between
+// DYNAMIC_START and DYNAMIC_END, with unmapped guard gaps between regions so
+// running off the end of one faults rather than scribbling on the next.
+//   see: https://wiki.osdev.org/Memory_Allocation
+// A region can also be backed lazily: nothing is mapped up front and the
+// first touch of each page page faults, which `handle_page_fault` resolves by
+// mapping a zeroed frame and letting the faulting instruction run again. Big
+// buffers cost nothing but address space until they're used.
+//   see: https://en.wikipedia.org/wiki/Demand_paging
+// Regions are kept in a tree keyed by their start address. Its nodes come
+// from a small arena of their own rather than the heap, the heap is one of
+// the regions and may one day need to ask us for more room.
+use crate::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use crate::allocator::HeapAllocator;
+use crate::frame_allocator::GlobalFrameAllocator;
+use crate::{memory, serial_println};
+use alloc::collections::BTreeMap;
+use bootloader::bootinfo::MemoryMap;
+use core::alloc::{AllocError, Allocator, Layout};
+use core::fmt;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTable, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Where regions without a fixed address are put.
+pub const DYNAMIC_START: u64 = 0x_5555_0000_0000;
+pub const DYNAMIC_END: u64 = 0x_5600_0000_0000;
+/// Unmapped space left before and after every dynamic region.
+pub const GUARD_SIZE: u64 = Size4KiB::SIZE;
+/// How many regions can be tracked.
+const MAX_REGIONS: usize = 256;
+/// Memory for the tree's nodes, enough for MAX_REGIONS.
+const ARENA_SIZE: usize = 128 * 1024;
+/// Virtual memory covered by one P4 entry.
+const P4_ENTRY_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+/// What a region is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    PhysicalMemory,
+    Heap,
+    Stack,
+    Mmio,
+    /// Left to the processes, see `address_space`.
+    User,
+    /// Mapped by the bootloader for its own reasons.
+    Boot,
+    Other,
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Padded so the dump lines up
+        f.pad(match self {
+            RegionKind::PhysicalMemory => "physical memory",
+            RegionKind::Heap => "heap",
+            RegionKind::Stack => "stack",
+            RegionKind::Mmio => "mmio",
+            RegionKind::User => "user",
+            RegionKind::Boot => "boot",
+            RegionKind::Other => "other",
+        })
+    }
+}
+
+/// A reserved range of virtual memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub kind: RegionKind,
+    pub name: &'static str,
+    /// How the region is mapped, for regions `map_region` backed with
+    /// frames of their own.
+    pub flags: Option<PageTableFlags>,
+    /// Bytes at the bottom of the region deliberately left unmapped, so
+    /// a stack growing down into them faults.
+    pub guard: u64,
+    /// Frames are only mapped when a page is first touched.
+    pub lazy: bool,
+}
+
+impl Region {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Whether `addr` is in the unmapped guard at the bottom of the region.
+    pub fn in_guard(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.start + self.guard
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} {:>10} KiB {:<15} {}",
+            self.start.as_u64(),
+            self.end().as_u64(),
+            self.size / 1024,
+            self.kind,
+            self.name
+        )?;
+        if let Some(flags) = self.flags {
+            write!(f, " {:?}", flags)?;
+        }
+        if self.guard > 0 {
+            write!(f, " guard {} KiB", self.guard / 1024)?;
+        }
+        if self.lazy {
+            write!(f, " lazy")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    /// The range overlaps a region that is already reserved.
+    Overlap(Region),
+    /// There is no gap big enough in the dynamic range.
+    NoSpace,
+    TooManyRegions,
+    /// No region starts at that address.
+    NotFound,
+    /// The region wasn't mapped by `map_region` or `map_lazy_region`.
+    NotOwned(Region),
+    OutOfFrames,
+    Map(MapToError<Size4KiB>),
+}
+
+lazy_static! {
+    static ref REGIONS: Mutex<BTreeMap<u64, Region, &'static Arena>> =
+        Mutex::new(BTreeMap::new_in(&ARENA));
+}
+
+/// Reserve the fixed parts of the layout. Call once the heap and APICs are
+/// set up.
+pub fn init(memory_map: &MemoryMap) {
+    unsafe {
+        ARENA
+            .blocks
+            .lock()
+            .init(core::ptr::addr_of_mut!(ARENA_MEMORY.0) as usize, ARENA_SIZE)
+    };
+
+    let physical_memory_end = memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    let fixed = [
+        (
+            memory::physical_memory_offset(),
+            physical_memory_end,
+            RegionKind::PhysicalMemory,
+            "physical memory",
+        ),
+        (
+            VirtAddr::new(crate::allocator::HEAP_START as u64),
+            crate::allocator::HEAP_RESERVED as u64,
+            RegionKind::Heap,
+            "kernel heap",
+        ),
+        (
+            VirtAddr::new(crate::apic::APIC_MMIO_START),
+            crate::apic::APIC_MMIO_SIZE,
+            RegionKind::Mmio,
+            "APIC registers",
+        ),
+    ];
+    for (start, size, kind, name) in fixed {
+        reserve(start, size, kind, name).expect("kernel layout overlaps itself");
+    }
+
+    // Whatever else is mapped came from the bootloader, keep out of its way
+    let (level_4_table_frame, _) = Cr3::read();
+    let level_4_table: &PageTable = unsafe {
+        &*(memory::physical_memory_offset() + level_4_table_frame.start_address().as_u64()).as_ptr()
+    };
+    for (index, entry) in level_4_table.iter().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let start = VirtAddr::new_truncate(index as u64 * P4_ENTRY_SIZE);
+        // The slots the fixed regions are in fail to reserve, that's fine
+        let _ = reserve(start, P4_ENTRY_SIZE, RegionKind::Boot, "boot");
+    }
+}
+
+/// Reserve `size` bytes at `start` so nothing else will be put there.
+pub fn reserve(
+    start: VirtAddr,
+    size: u64,
+    kind: RegionKind,
+    name: &'static str,
+) -> Result<Region, VmaError> {
+    let region = Region {
+        start,
+        size,
+        kind,
+        name,
+        flags: None,
+        guard: 0,
+        lazy: false,
+    };
+    interrupts::without_interrupts(|| insert(&mut REGIONS.lock(), region))
+}
+
+/// Find room for `size` bytes, aligned to `align`, in the dynamic range and
+/// reserve it. There will be at least GUARD_SIZE bytes of unreserved space on
+/// either side.
+pub fn allocate(
+    size: u64,
+    align: u64,
+    kind: RegionKind,
+    name: &'static str,
+) -> Result<Region, VmaError> {
+    interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let start = find_gap(&regions, size, align).ok_or(VmaError::NoSpace)?;
+        let region = Region {
+            start,
+            size,
+            kind,
+            name,
+            flags: None,
+            guard: 0,
+            lazy: false,
+        };
+        insert(&mut regions, region)
+    })
+}
+
+/// Give back the region starting at `start`. Whatever is mapped there
+/// stays mapped.
+pub fn release(start: VirtAddr) -> Result<Region, VmaError> {
+    interrupts::without_interrupts(|| {
+        REGIONS
+            .lock()
+            .remove(&start.as_u64())
+            .ok_or(VmaError::NotFound)
+    })
+}
+
+/// The region `addr` is in, if any.
+pub fn region_containing(addr: VirtAddr) -> Option<Region> {
+    interrupts::without_interrupts(|| containing(&REGIONS.lock(), addr))
+}
+
+/// Like `region_containing` but gives up rather than wait for the lock,
+/// for exception handlers that may have interrupted a change to the layout.
+pub fn try_region_containing(addr: VirtAddr) -> Option<Region> {
+    let regions = REGIONS.try_lock()?;
+    containing(&regions, addr)
+}
+
+/// Allocate a dynamic region of `size` bytes and map fresh frames to all of
+/// it with `flags`.
+pub fn map_region(
+    size: u64,
+    flags: PageTableFlags,
+    kind: RegionKind,
+    name: &'static str,
+) -> Result<Region, VmaError> {
+    map_guarded_region(size, 0, flags, kind, name)
+}
+
+/// Like `map_region`, but the region gets another `guard` bytes below the
+/// mapped part that are left unmapped. For stacks, which grow down.
+pub fn map_guarded_region(
+    size: u64,
+    guard: u64,
+    flags: PageTableFlags,
+    kind: RegionKind,
+    name: &'static str,
+) -> Result<Region, VmaError> {
+    let size = align_up(size, Size4KiB::SIZE);
+    let guard = align_up(guard, Size4KiB::SIZE);
+    let mut region = allocate(guard + size, Size4KiB::SIZE, kind, name)?;
+    region.flags = Some(flags);
+    region.guard = guard;
+    let mapped = memory::with_mapper(|mapper| {
+        for (i, page) in mapped_pages(&region).enumerate() {
+            let mapped = match GlobalFrameAllocator.allocate_frame() {
+                Some(frame) => unsafe {
+                    mapper
+                        .map_to(page, frame, flags, &mut GlobalFrameAllocator)
+                        .map(|flush| flush.flush())
+                        .map_err(|err| {
+                            GlobalFrameAllocator.deallocate_frame(frame);
+                            VmaError::Map(err)
+                        })
+                },
+                None => Err(VmaError::OutOfFrames),
+            };
+            if let Err(err) = mapped {
+                unmap_pages(mapper, mapped_pages(&region).take(i));
+                return Err(err);
+            }
+        }
+        Ok(())
+    });
+    if let Err(err) = mapped {
+        let _ = release(region.start);
+        return Err(err);
+    }
+    interrupts::without_interrupts(|| REGIONS.lock().insert(region.start.as_u64(), region));
+    Ok(region)
+}
+
+/// Reserve a dynamic region of `size` bytes that gets a zeroed frame mapped
+/// with `flags` the first time each of its pages is touched.
+pub fn map_lazy_region(
+    size: u64,
+    flags: PageTableFlags,
+    kind: RegionKind,
+    name: &'static str,
+) -> Result<Region, VmaError> {
+    let size = align_up(size, Size4KiB::SIZE);
+    let mut region = allocate(size, Size4KiB::SIZE, kind, name)?;
+    region.flags = Some(flags);
+    region.lazy = true;
+    interrupts::without_interrupts(|| REGIONS.lock().insert(region.start.as_u64(), region));
+    Ok(region)
+}
+
+/// Try to resolve a page fault at `addr` by backing the page with a zeroed
+/// frame. Returns false for faults that aren't the first touch of a lazy
+/// region, or happened while the layout or the page tables were being
+/// changed, and have to be reported as they are.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let Some(region) = try_region_containing(addr) else {
+        return false;
+    };
+    let Some(flags) = region.flags.filter(|_| region.lazy) else {
+        return false;
+    };
+    if region.in_guard(addr) || !allowed(error_code, flags) {
+        return false;
+    }
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let resolved = memory::try_with_mapper(|mapper| {
+        let Some(frame) = GlobalFrameAllocator.allocate_frame() else {
+            return false;
+        };
+        unsafe {
+            let ptr: *mut u8 =
+                (memory::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+            ptr.write_bytes(0, Size4KiB::SIZE as usize);
+            match mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                // Someone got there first, just try again
+                Err(MapToError::PageAlreadyMapped(_)) => {
+                    GlobalFrameAllocator.deallocate_frame(frame);
+                    true
+                }
+                Err(_) => {
+                    GlobalFrameAllocator.deallocate_frame(frame);
+                    false
+                }
+            }
+        }
+    });
+    if resolved == Some(true) {
+        DEMAND_FAULTS.fetch_add(1, Ordering::Relaxed);
+        return true;
+    }
+    false
+}
+
+/// How many page faults have been resolved by mapping a fresh frame.
+pub fn demand_faults() -> u64 {
+    DEMAND_FAULTS.load(Ordering::Relaxed)
+}
+
+static DEMAND_FAULTS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a fault with `error_code` is just a missing page that mapping it
+/// with `flags` would let through.
+fn allowed(error_code: PageFaultErrorCode, flags: PageTableFlags) -> bool {
+    // The page was there and the access wasn't allowed
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    // A reserved bit set in a page table entry, something is badly wrong
+    if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        return false;
+    }
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !flags.contains(PageTableFlags::WRITABLE)
+    {
+        return false;
+    }
+    if error_code.contains(PageFaultErrorCode::USER_MODE)
+        && !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    {
+        return false;
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && flags.contains(PageTableFlags::NO_EXECUTE)
+    {
+        return false;
+    }
+    true
+}
+
+/// Unmap a region made by `map_region`, free its frames, and give back its
+/// address range.
+pub fn unmap_region(start: VirtAddr) -> Result<(), VmaError> {
+    let region = interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let region = *regions.get(&start.as_u64()).ok_or(VmaError::NotFound)?;
+        if region.flags.is_none() {
+            return Err(VmaError::NotOwned(region));
+        }
+        regions.remove(&start.as_u64());
+        Ok(region)
+    })?;
+    memory::with_mapper(|mapper| unmap_pages(mapper, mapped_pages(&region)));
+    Ok(())
+}
+
+/// Print every region and the gaps between them to serial.
+pub fn dump() {
+    serial_println!("Kernel virtual memory layout:");
+    let mut end = 0;
+    for i in 0..MAX_REGIONS {
+        // One at a time so we don't hold the lock while printing
+        let Some(region) =
+            interrupts::without_interrupts(|| REGIONS.lock().values().nth(i).copied())
+        else {
+            break;
+        };
+        if region.start.as_u64() > end {
+            let gap = region.start.as_u64() - end;
+            serial_println!(
+                "{:#018x}-{:#018x} {:>10} KiB free",
+                end,
+                region.start.as_u64(),
+                gap / 1024
+            );
+        }
+        serial_println!("{}", region);
+        end = region.end().as_u64();
+    }
+}
+
+fn insert(
+    regions: &mut BTreeMap<u64, Region, &'static Arena>,
+    region: Region,
+) -> Result<Region, VmaError> {
+    if regions.len() >= MAX_REGIONS {
+        return Err(VmaError::TooManyRegions);
+    }
+    let end = region.end().as_u64();
+    // The region starting closest below the end is the only one that can
+    // overlap, the tree never holds overlapping regions
+    if let Some((_, other)) = regions.range(..end).next_back() {
+        if other.end() > region.start {
+            return Err(VmaError::Overlap(*other));
+        }
+    }
+    regions.insert(region.start.as_u64(), region);
+    Ok(region)
+}
+
+fn containing(regions: &BTreeMap<u64, Region, &'static Arena>, addr: VirtAddr) -> Option<Region> {
+    let (_, region) = regions.range(..=addr.as_u64()).next_back()?;
+    region.contains(addr).then_some(*region)
+}
+
+/// The lowest address in the dynamic range with room for `size` bytes and
+/// guard gaps around them.
+fn find_gap(
+    regions: &BTreeMap<u64, Region, &'static Arena>,
+    size: u64,
+    align: u64,
+) -> Option<VirtAddr> {
+    let mut free_from = DYNAMIC_START;
+    // Regions starting before the dynamic range may still reach into it
+    let before = regions.range(..DYNAMIC_START).next_back().map(|(_, r)| r);
+    let within = regions.range(DYNAMIC_START..DYNAMIC_END).map(|(_, r)| r);
+    for region in before.into_iter().chain(within) {
+        let start = align_up(free_from + GUARD_SIZE, align);
+        if start + size + GUARD_SIZE <= region.start.as_u64() {
+            return Some(VirtAddr::new(start));
+        }
+        free_from = free_from.max(region.end().as_u64());
+    }
+    let start = align_up(free_from + GUARD_SIZE, align);
+    (start + size + GUARD_SIZE <= DYNAMIC_END).then(|| VirtAddr::new(start))
+}
+
+/// Every page of `region` above its guard.
+fn mapped_pages(region: &Region) -> impl Iterator<Item = Page<Size4KiB>> {
+    let first = Page::containing_address(region.start + region.guard);
+    let last = Page::containing_address(region.end() - 1u64);
+    Page::range_inclusive(first, last)
+}
+
+fn unmap_pages(mapper: &mut impl Mapper<Size4KiB>, pages: impl Iterator<Item = Page<Size4KiB>>) {
+    for page in pages {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Backing for the region tree.
+#[repr(align(4096))]
+struct ArenaMemory([u8; ARENA_SIZE]);
+
+static mut ARENA_MEMORY: ArenaMemory = ArenaMemory([0; ARENA_SIZE]);
+static ARENA: Arena = Arena {
+    blocks: Mutex::new(FixedSizeBlockAllocator::EMPTY),
+};
+
+/// Hands out the tree's nodes from ARENA_MEMORY.
+struct Arena {
+    blocks: Mutex<FixedSizeBlockAllocator>,
+}
+
+unsafe impl Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.blocks.lock().allocate(layout);
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.blocks.lock().deallocate(ptr.as_ptr(), layout)
+    }
+}
+
+#[test_case]
+fn test_map_and_unmap_region() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let a = map_region(3 * 4096, flags, RegionKind::Other, "test a").unwrap();
+    let b = map_region(4096, flags, RegionKind::Other, "test b").unwrap();
+    // Kept apart by a guard gap
+    assert!(b.start >= a.end() + GUARD_SIZE || a.start >= b.end() + GUARD_SIZE);
+    assert_eq!(region_containing(a.start + 5000u64), Some(a));
+    assert_eq!(region_containing(a.end()), None);
+    unsafe { (a.end() - 8u64).as_mut_ptr::<u64>().write_volatile(1) };
+    assert!(matches!(
+        reserve(a.start + 4096u64, 4096, RegionKind::Other, "test c"),
+        Err(VmaError::Overlap(region)) if region == a
+    ));
+    unmap_region(a.start).unwrap();
+    unmap_region(b.start).unwrap();
+    assert_eq!(region_containing(a.start), None);
+}
+
+#[test_case]
+fn test_lazy_region() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let size = 64 * 1024 * 1024;
+    let used = crate::frame_allocator::stats().used_frames;
+    let region = map_lazy_region(size, flags, RegionKind::Other, "test lazy").unwrap();
+    // Nothing is backed until it's touched
+    assert!(crate::frame_allocator::stats().used_frames < used + 16);
+    let faults = demand_faults();
+    for offset in [0, size / 2, size - 8] {
+        let ptr = (region.start + offset).as_mut_ptr::<u64>();
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(offset);
+            assert_eq!(ptr.read_volatile(), offset);
+        }
+    }
+    assert_eq!(demand_faults(), faults + 3);
+    unmap_region(region.start).unwrap();
+    assert_eq!(region_containing(region.start), None);
+}
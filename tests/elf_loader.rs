@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(greg_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use greg_os::elf::{self, ElfError, ProgramHeader};
+use greg_os::user::KILLED_EXIT_CODE;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    greg_os::init(boot_info);
+    test_main();
+    greg_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    greg_os::test_panic_handler(info)
+}
+
+// Built from programs/exit_code.s
+static EXIT_CODE: &[u8] = include_bytes!("programs/exit_code.elf");
+
+/// The segment with .data and .bss, readable and writable.
+fn data_segment() -> ProgramHeader {
+    let header = elf::parse_header(EXIT_CODE).unwrap();
+    elf::program_headers(EXIT_CODE, &header)
+        // PF_R | PF_W
+        .find(|ph| ph.flags == 6)
+        .unwrap()
+}
+
+#[test_case]
+fn run_static_executable() {
+    let program = elf::load(EXIT_CODE, &["exit_code", "a", "b"], &["HOME=/"]).unwrap();
+    // argc * 10 + 1 environment variable + 7 from .data
+    assert_eq!(program.run().unwrap(), 38);
+}
+
+#[test_case]
+fn run_without_arguments() {
+    let program = elf::load(EXIT_CODE, &[], &[]).unwrap();
+    assert_eq!(program.run().unwrap(), 7);
+}
+
+#[test_case]
+fn reject_truncated_executable() {
+    assert!(matches!(
+        elf::load(&EXIT_CODE[..100], &[], &[]),
+        Err(ElfError::Truncated)
+    ));
+}
+
+#[test_case]
+fn reject_segment_outside_file() {
+    let mut image = alloc::vec::Vec::from(EXIT_CODE);
+    // Make the first segment claim more of the file than there is
+    let phoff = u64::from_le_bytes(image[32..40].try_into().unwrap()) as usize;
+    image[phoff + 32..phoff + 40].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(matches!(
+        elf::load(&image, &[], &[]),
+        Err(ElfError::BadSegment(_))
+    ));
+}
+
+#[test_case]
+fn reject_bad_entry_point() {
+    let data = data_segment();
+    // Not canonical, in the kernel, and in the read-write segment
+    for entry in [0x8000_0000_0000, 0xffff_8000_0000_0000, data.vaddr] {
+        let mut image = alloc::vec::Vec::from(EXIT_CODE);
+        image[24..32].copy_from_slice(&entry.to_le_bytes());
+        assert!(matches!(
+            elf::load(&image, &[], &[]),
+            Err(ElfError::BadEntry(bad)) if bad == entry
+        ));
+    }
+}
+
+#[test_case]
+fn data_is_not_executable() {
+    let header = elf::parse_header(EXIT_CODE).unwrap();
+    let text = elf::program_headers(EXIT_CODE, &header)
+        .find(|ph| (ph.vaddr..ph.vaddr + ph.memsz).contains(&header.entry))
+        .unwrap();
+    let mut image = alloc::vec::Vec::from(EXIT_CODE);
+    // Start with `movabs rax, <.data>; jmp rax`
+    let at = (text.offset + header.entry - text.vaddr) as usize;
+    image[at..at + 2].copy_from_slice(&[0x48, 0xb8]);
+    image[at + 2..at + 10].copy_from_slice(&data_segment().vaddr.to_le_bytes());
+    image[at + 10..at + 12].copy_from_slice(&[0xff, 0xe0]);
+    let program = elf::load(&image, &[], &[]).unwrap();
+    // Killed by the page fault fetching from a no-execute page
+    assert_eq!(program.run().unwrap(), KILLED_EXIT_CODE + 14);
+}
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(greg_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use greg_os::task::executor::Executor;
+use greg_os::task::yield_now;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    greg_os::init(boot_info);
+    test_main();
+    greg_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    greg_os::test_panic_handler(info)
+}
+
+const TASKS: usize = 5000;
+
+#[test_case]
+fn chain_of_tasks() {
+    // Each task waits for the one before it, all queued at once
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let mut previous = spawner.spawn(async { 0 });
+    for _ in 1..TASKS {
+        previous = spawner.spawn(async move {
+            yield_now().await;
+            previous.await.unwrap() + 1
+        });
+    }
+    executor.run_until_idle();
+    assert!(previous.is_finished());
+    assert_eq!(executor.tasks().count(), 0);
+}
@@ -63,3 +63,36 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1); // new
 }
+
+#[test_case]
+fn heap_grows_past_initial_size() {
+    let mut vec = Vec::with_capacity(HEAP_SIZE * 4);
+    for i in 0..HEAP_SIZE * 4 {
+        vec.push(i as u8);
+    }
+    assert_eq!(vec[HEAP_SIZE * 3], (HEAP_SIZE * 3) as u8);
+    assert!(greg_os::allocator::heap_size() > HEAP_SIZE);
+}
+
+#[test_case]
+fn fragmented_allocations_are_freed() {
+    use greg_os::allocator::stats;
+
+    // Mixed lifetimes so the heap fragments, timed to compare the designs
+    let before = stats::stats();
+    let start = greg_os::pit::uptime();
+    let mut boxes = Vec::new();
+    for i in 0..10_000usize {
+        boxes.push(Box::new([i; 4]));
+        if i % 3 == 0 {
+            let victim = i / 2 % boxes.len();
+            boxes.swap_remove(victim);
+        }
+    }
+    drop(boxes);
+    let elapsed = greg_os::pit::uptime() - start;
+    greg_os::serial_print!("({} heap took {:?}) ", greg_os::allocator::BACKEND, elapsed);
+    let after = stats::stats();
+    assert_eq!(after.live_bytes, before.live_bytes);
+    assert_eq!(after.live_allocations(), before.live_allocations());
+}
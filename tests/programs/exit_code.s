@@ -0,0 +1,53 @@
+# A tiny static program for tests/elf_loader.rs. It checks the stack the
+# loader built and exits with argc * 10 + the number of environment
+# variables + a value from .data passed through .bss, or 255 if the auxiliary
+# vector has no AT_PAGESZ of 4096.
+# Build with:
+#   as -o exit_code.o exit_code.s
+#   ld -static -nostdlib -s -z max-page-size=4096 -z separate-code \
+#      -Ttext-segment=0x100000000000 -o exit_code.elf exit_code.o
+.intel_syntax noprefix
+.globl _start
+
+.text
+_start:
+    mov rbx, [rsp]                  # argc
+    lea rcx, [rsp + rbx * 8 + 16]   # envp, after argv and its NULL
+    xor edx, edx
+count_env:
+    cmp qword ptr [rcx + rdx * 8], 0
+    je auxv
+    inc rdx
+    jmp count_env
+auxv:
+    lea rcx, [rcx + rdx * 8 + 8]    # after envp's NULL
+find_pagesz:
+    mov rax, [rcx]
+    test rax, rax                   # AT_NULL
+    jz fail
+    cmp rax, 6                      # AT_PAGESZ
+    je check_pagesz
+    add rcx, 16
+    jmp find_pagesz
+check_pagesz:
+    cmp qword ptr [rcx + 8], 4096
+    jne fail
+    mov rax, [rip + value]
+    mov [rip + copy], rax
+    imul rdi, rbx, 10
+    add rdi, rdx
+    add rdi, [rip + copy]
+    mov eax, 2                      # SYS_EXIT
+    syscall
+fail:
+    mov edi, 255
+    mov eax, 2
+    syscall
+
+.data
+value:
+    .quad 7
+
+.bss
+copy:
+    .quad 0
@@ -0,0 +1,87 @@
+// Test that overflowing a guarded kernel stack is reported with its name
+#![feature(abi_x86_interrupt)]
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use greg_os::gdt::DOUBLE_FAULT_IST_INDEX;
+use greg_os::stack::{self, KernelStack};
+use greg_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+// Setup an IDT that just has a double fault handler
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_guard::stack_guard...\t");
+    // Sets up the guarded interrupt stacks too
+    greg_os::init(boot_info);
+    let stack = KernelStack::new(stack::DEFAULT_STACK_SIZE, "overflow test").unwrap();
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+    // Move onto the new stack and run off the end of it
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "call {overflow}",
+            top = in(reg) stack.top().as_u64(),
+            overflow = sym overflow_entry,
+            options(noreturn)
+        )
+    }
+}
+
+extern "C" fn overflow_entry() -> ! {
+    stack_overflow();
+    // We should never get here
+    panic!("Execution continued after stack overflow");
+}
+
+// This function forces a stack overflow by calling itself recursively
+// and forcing no tail call elimination.
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    // for each recursion, the return address is pushed to the stack
+    stack_overflow();
+    // force a read to prevent tail call elimination
+    unsafe { core::ptr::read_volatile(core::ptr::null_mut::<usize>()) };
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    match stack::overflowed_stack() {
+        Some("overflow test") => {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        other => {
+            serial_println!("[failed]");
+            serial_println!("Error: overflow reported in {:?}", other);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    greg_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    greg_os::test_panic_handler(info)
+}
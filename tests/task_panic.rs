@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use greg_os::task::{self, executor::Executor, Task, TaskId};
+use greg_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use spin::Mutex;
+
+/// The task that is about to panic.
+static PANICKING: Mutex<Option<TaskId>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("task_panic::panicking_task_is_named...\t");
+    greg_os::init(boot_info);
+
+    let mut executor = Executor::new();
+    let id = executor.spawn(Task::new(async { panic!("task panicked") }));
+    *PANICKING.lock() = Some(id);
+    executor.run();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let expected = *PANICKING.lock();
+    if expected.is_some() && task::current() == expected {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Error: {}, in task {:?}", info, task::current());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    greg_os::hlt_loop();
+}
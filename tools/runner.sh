@@ -0,0 +1,34 @@
+#!/bin/sh
+# Cargo runner for the kernel and its tests.
+# Fills the kernel's `.ksyms` section with a table of function addresses and
+# demangled names so backtraces can show where we are (see src/backtrace.rs),
+# then hands over to `bootimage runner` as before.
+# Needs the llvm tools: `rustup component add llvm-tools-preview`
+set -e
+
+kernel="$1"
+host="$(rustc -vV | sed -n 's/^host: //p')"
+llvm_bin="$(rustc --print sysroot)/lib/rustlib/$host/bin"
+table="$kernel.ksyms"
+
+# Keep only functions, formatted as "<16 hex digit address> <name>" sorted by
+# address, dropping the hash suffix rustc adds to every symbol.
+"$llvm_bin/llvm-nm" --defined-only --demangle --numeric-sort "$kernel" \
+    | sed -n 's/^\([0-9a-f]\{16\}\) [tTwW] \(.*\)$/\1 \2/p' \
+    | sed 's/::h[0-9a-f]\{16\}$//' > "$table"
+# The kernel reads the table up to the first NUL
+printf '\0' >> "$table"
+
+# A table too big for the section is left out rather than stopping the run,
+# backtraces just show bare addresses then
+section_size="$("$llvm_bin/llvm-objdump" -h "$kernel" | awk '$2 == ".ksyms" { print $3 }')"
+section_size="$(printf '%d' "0x${section_size:-0}")"
+table_size="$(wc -c < "$table")"
+if [ "$table_size" -le "$section_size" ]; then
+    "$llvm_bin/llvm-objcopy" --update-section .ksyms="$table" "$kernel"
+else
+    echo "warning: symbol table is $table_size bytes but .ksyms only has room" \
+        "for $section_size, raise SYMBOL_TABLE_SIZE in src/backtrace.rs" >&2
+fi
+
+exec bootimage runner "$@"